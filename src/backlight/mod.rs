@@ -30,21 +30,87 @@ pub trait Backlight {
 
 	/// Set the backlight value as a percentage.
 	fn set(&mut self, value: f32) -> error::Result<()>;
+
+	/// The output the backlight belongs to, if known.
+	fn output(&self) -> Option<xcb::randr::Output> {
+		None
+	}
+
+	/// The backend handling the backlight.
+	fn backend(&self) -> Backend;
 }
 
 mod randr;
 mod sys;
 
-/// Open the first available backlight handler.
-pub fn open(display: Arc<Display>) -> error::Result<Box<dyn Backlight>> {
-	if let Ok(backlight) = randr::Backlight::open(display.clone()) {
-		Ok(Box::new(backlight))
+/// Supported backlight handlers.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Backend {
+	/// The XRandr `Backlight` output property.
+	Randr,
+
+	/// The `/sys/class/backlight` interface.
+	Sys,
+}
+
+impl Backend {
+	pub fn parse<T: AsRef<str>>(value: T) -> Option<Backend> {
+		match value.as_ref() {
+			"randr" => Some(Backend::Randr),
+			"sys"   => Some(Backend::Sys),
+			_       => None,
+		}
 	}
-	else if let Ok(backlight) = sys::Backlight::open() {
-		Ok(Box::new(backlight))
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Backend::Randr => "randr",
+			Backend::Sys   => "sys",
+		}
 	}
-	else {
-		Err(error::Error::Unsupported)
+}
+
+/// Open the given backlight handler, or the first available one.
+pub fn open(display: Arc<Display>, backend: Option<Backend>) -> error::Result<Box<dyn Backlight>> {
+	match backend {
+		Some(Backend::Randr) =>
+			Ok(Box::new(randr::Backlight::open(display)?)),
+
+		Some(Backend::Sys) =>
+			Ok(Box::new(sys::Backlight::open(&display)?)),
+
+		None => {
+			if let Ok(backlight) = randr::Backlight::open(display.clone()) {
+				Ok(Box::new(backlight))
+			}
+			else if let Ok(backlight) = sys::Backlight::open(&display) {
+				Ok(Box::new(backlight))
+			}
+			else {
+				Err(error::Error::Unsupported)
+			}
+		}
+	}
+}
+
+/// Open a backlight handler for every output that supports it, the first one
+/// is the same returned by `open`.
+pub fn all(display: Arc<Display>, backend: Option<Backend>) -> error::Result<Vec<Box<dyn Backlight>>> {
+	fn randr(display: Arc<Display>) -> error::Result<Vec<Box<dyn Backlight>>> {
+		Ok(randr::Backlight::all(display)?.into_iter()
+			.map(|b| Box::new(b) as Box<dyn Backlight>)
+			.collect())
+	}
+
+	match backend {
+		Some(Backend::Randr) =>
+			randr(display),
+
+		Some(Backend::Sys) =>
+			Ok(vec![Box::new(sys::Backlight::open(&display)?)]),
+
+		None =>
+			randr(display.clone()).or_else(|_| Ok(vec![Box::new(sys::Backlight::open(&display)?)]))
 	}
 }
 
@@ -31,7 +31,12 @@ pub struct Backlight {
 
 impl Backlight {
 	pub fn open(display: Arc<Display>) -> error::Result<Self> {
-		fn find(display: &Display) -> error::Result<(xcb::randr::Output, xcb::Atom)> {
+		Backlight::all(display)?.into_iter().next().ok_or(error::Error::Unsupported)
+	}
+
+	/// Open the backlight of every output that has one.
+	pub fn all(display: Arc<Display>) -> error::Result<Vec<Self>> {
+		fn find(display: &Display) -> error::Result<Vec<(xcb::randr::Output, xcb::Atom)>> {
 			let current = xcb::intern_atom(display, true, "Backlight").get_reply().ok()
 				.and_then(|r| if r.atom() != xcb::ATOM_NONE { Some(r.atom()) } else { None })
 				.ok_or(error::Error::Unsupported)?;
@@ -40,6 +45,8 @@ impl Backlight {
 				.and_then(|r| if r.atom() != xcb::ATOM_NONE { Some(r.atom()) } else { None })
 				.ok_or(error::Error::Unsupported)?;
 
+			let mut result = Vec::new();
+
 			for &id in xcb::randr::get_screen_resources_current(display, display.root()).get_reply()?.outputs() {
 				let reply = if let Ok(r) = xcb::randr::get_output_property(display, id, current, xcb::ATOM_NONE, 0, 4, false, false).get_reply() {
 					Some((r, current))
@@ -53,19 +60,25 @@ impl Backlight {
 
 				if let Some((reply, atom)) = reply {
 					if reply.type_() == xcb::ATOM_INTEGER && reply.num_items() == 1 && reply.format() == 32 {
-						return Ok((id, atom));
+						result.push((id, atom));
 					}
 				}
 			}
 
-			Err(error::Error::Unsupported)
+			if result.is_empty() {
+				Err(error::Error::Unsupported)
+			}
+			else {
+				Ok(result)
+			}
 		}
 
-		let (output, atom) = find(&display)?;
-		let range          = xcb::randr::query_output_property(&display, output, atom).get_reply().map(|reply|
-			(reply.valid_values()[0], reply.valid_values()[1]))?;
+		find(&display)?.into_iter().map(|(output, atom)| {
+			let range = xcb::randr::query_output_property(&display, output, atom).get_reply().map(|reply|
+				(reply.valid_values()[0], reply.valid_values()[1]))?;
 
-		Ok(Backlight { display, output, atom, range })
+			Ok(Backlight { display: display.clone(), output, atom, range })
+		}).collect()
 	}
 }
 
@@ -89,4 +102,12 @@ impl super::Backlight for Backlight {
 
 		Ok(())
 	}
+
+	fn output(&self) -> Option<xcb::randr::Output> {
+		Some(self.output)
+	}
+
+	fn backend(&self) -> super::Backend {
+		super::Backend::Randr
+	}
 }
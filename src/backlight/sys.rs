@@ -19,15 +19,16 @@ use std::fs::{self, File};
 use std::path::PathBuf;
 use std::io::{Write, Read};
 
-use crate::error;
+use crate::{Display, error};
 
 pub struct Backlight {
-	path: PathBuf,
-	max:  u32,
+	path:   PathBuf,
+	max:    u32,
+	output: Option<xcb::randr::Output>,
 }
 
 impl Backlight {
-	pub fn open() -> error::Result<Self> {
+	pub fn open(display: &Display) -> error::Result<Self> {
 		let root = fs::read_dir("/sys/class/backlight")?.next().ok_or(error::Error::Unsupported)??.path();
 		let max  = {
 			let mut file    = File::open(root.join("max_brightness"))?;
@@ -37,9 +38,16 @@ impl Backlight {
 			content.trim().parse::<u32>().or(Err(error::Error::Unsupported))?
 		};
 
+		// The kernel doesn't tell which output the backlight belongs to, but it's
+		// pretty much always the builtin panel.
+		let output = display.outputs().ok()
+			.and_then(|outputs| outputs.into_iter().find(|o| o.is_panel()))
+			.map(|o| o.id);
+
 		Ok(Backlight {
 			path: root.join("brightness"),
 			max,
+			output,
 		})
 	}
 }
@@ -64,4 +72,12 @@ impl super::Backlight for Backlight {
 
 		Ok(())
 	}
+
+	fn output(&self) -> Option<xcb::randr::Output> {
+		self.output
+	}
+
+	fn backend(&self) -> super::Backend {
+		super::Backend::Sys
+	}
 }
@@ -33,24 +33,55 @@ use crate::{Display, error};
 /// It supports multiple profiles and takes care of saving the brightness
 /// values appropriately for each `Mode`.
 pub struct Cache {
-	display: Arc<Display>,
-	data:    JsonValue,
-	path:    PathBuf,
-	profile: String,
+	display:   Option<Arc<Display>>,
+	data:      JsonValue,
+	path:      PathBuf,
+	profile:   String,
+	transient: Transient,
 }
 
 /// Supported modes.
+#[derive(Clone)]
 pub enum Mode {
 	Manual,
-	Desktop(i32),
+	Desktop(Desktop),
 	Window(Option<xcb::Window>),
 	Luminance(f32),
 	Time(chrono::DateTime<chrono::Local>),
 }
 
+/// A desktop, known by its name when it has one.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Desktop {
+	pub index: i32,
+	pub name:  Option<String>,
+}
+
+/// How dialogs, popups and other transient windows are handled in window mode.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum Transient {
+	/// Use the brightness of the window they belong to.
+	#[default]
+	Inherit,
+
+	/// Keep the brightness as it is.
+	Ignore,
+}
+
+impl Transient {
+	pub fn parse<T: AsRef<str>>(value: T) -> Option<Transient> {
+		match value.as_ref() {
+			"inherit" => Some(Transient::Inherit),
+			"ignore"  => Some(Transient::Ignore),
+			_         => None,
+		}
+	}
+}
+
 impl Cache {
-	/// Open the cache at the given path.
-	pub fn open<T: AsRef<Path>>(display: Arc<Display>, path: Option<T>) -> error::Result<Self> {
+	/// Open the cache at the given path, without a display the window mode is
+	/// not available.
+	pub fn open<T: AsRef<Path>>(display: Option<Arc<Display>>, path: Option<T>) -> error::Result<Self> {
 		// If no path was given we use the XDG standard places.
 		let path = if let Some(path) = path {
 			path.as_ref().into()
@@ -79,7 +110,8 @@ impl Cache {
 
 		Ok(Cache {
 			display, data, path,
-			profile: "default".into(),
+			profile:   "default".into(),
+			transient: Transient::default(),
 		})
 	}
 
@@ -100,6 +132,148 @@ impl Cache {
 		}
 	}
 
+	/// Get the current profile name.
+	pub fn current(&self) -> &str {
+		&self.profile
+	}
+
+	/// Get the names of the profiles with any learned value.
+	pub fn profiles(&self) -> Vec<String> {
+		self.data.entries().map(|(name, _)| name.to_owned()).collect()
+	}
+
+	/// Change how transient windows are handled.
+	pub fn transient(&mut self, value: Transient) {
+		self.transient = value;
+	}
+
+	/// Find the window whose brightness the given window uses, `None` if it
+	/// should be left alone.
+	///
+	/// Windows transient for another one, and dialogs, utility windows and
+	/// popups, follow their owners up to the first normal window.
+	fn owner(&self, active: Option<xcb::Window>) -> error::Result<Option<xcb::Window>> {
+		let display     = self.display.as_ref().ok_or(error::Error::Unsupported)?;
+		let mut current = if let Some(id) = active { id } else { return Ok(None) };
+
+		// The chain is limited in case of loops.
+		for _ in 0 .. 8 {
+			let parent = xcb::get_property(display, false, current, xcb::ATOM_WM_TRANSIENT_FOR, xcb::ATOM_WINDOW, 0, 1)
+				.get_reply()?.value::<xcb::Window>().first().cloned()
+				.filter(|&id| id != 0 && id != display.root());
+
+			let kind = xcb::get_property(display, false, current, display.WM_WINDOW_TYPE(), xcb::ATOM_ATOM, 0, 32)
+				.get_reply()?.value::<xcb::Atom>().to_vec();
+
+			let transient = parent.is_some() || kind.iter().any(|&atom|
+				atom == display.WM_WINDOW_TYPE_DIALOG() ||
+				atom == display.WM_WINDOW_TYPE_UTILITY() ||
+				atom == display.WM_WINDOW_TYPE_POPUP_MENU());
+
+			if !transient {
+				break;
+			}
+
+			if self.transient == Transient::Ignore {
+				return Ok(None);
+			}
+
+			// Dialogs can be transient for the whole application, in which case
+			// they're on their own.
+			if let Some(id) = parent {
+				current = id;
+			}
+			else {
+				break;
+			}
+		}
+
+		Ok(Some(current))
+	}
+
+	/// Get the section and key the brightness for the given desktop is stored
+	/// in.
+	///
+	/// Named desktops are stored by name so reordering them doesn't shuffle the
+	/// values, and the ones without a name by index.
+	fn desktop(desktop: &Desktop) -> (&'static str, String) {
+		if let Some(ref name) = desktop.name {
+			("workspace", name.clone())
+		}
+		else {
+			("desktop", desktop.index.to_string())
+		}
+	}
+
+	/// Move the values stored by index for the desktops that now have a name,
+	/// in every profile.
+	pub fn migrate(&mut self, desktops: &[Desktop]) {
+		let profiles = self.profiles();
+
+		for profile in &profiles {
+			for desktop in desktops {
+				let name = if let Some(ref name) = desktop.name {
+					name
+				}
+				else {
+					continue;
+				};
+
+				let index = desktop.index.to_string();
+
+				if self.data[profile]["desktop"][&index].is_null() {
+					continue;
+				}
+
+				let value = self.data[profile]["desktop"].remove(&index);
+
+				if self.data[profile]["workspace"].is_null() {
+					self.data[profile]["workspace"] = object!{};
+				}
+
+				if self.data[profile]["workspace"][name].is_null() {
+					self.data[profile]["workspace"][name] = value;
+				}
+			}
+		}
+	}
+
+	/// Get the WM_CLASS instance and class names of the given window.
+	fn class(&self, id: xcb::Window) -> error::Result<(String, String)> {
+		let display = self.display.as_ref().ok_or(error::Error::Unsupported)?;
+		let name    = xcbu::icccm::get_wm_class(display, id).get_reply()?;
+
+		Ok((name.instance().into(), name.class().into()))
+	}
+
+	/// Describe the given mode with its name and the key used to store the
+	/// brightness value.
+	pub fn describe(&self, mode: Mode) -> error::Result<(&'static str, JsonValue)> {
+		Ok(match mode {
+			Mode::Manual =>
+				("manual", JsonValue::Null),
+
+			Mode::Desktop(desktop) =>
+				("desktop", if let Some(name) = desktop.name { name.into() } else { desktop.index.into() }),
+
+			Mode::Window(active) => {
+				if let Some(id) = self.owner(active)? {
+					let (instance, class) = self.class(id)?;
+					("window", array![instance, class])
+				}
+				else {
+					("window", JsonValue::Null)
+				}
+			}
+
+			Mode::Luminance(luma) =>
+				("luminance", luma.into()),
+
+			Mode::Time(time) =>
+				("time", time.format("%H:%M").to_string().into()),
+		})
+	}
+
 	/// Set the brightness value for the given mode.
 	pub fn set(&mut self, mode: Mode, value: f32) -> error::Result<()> {
 		match mode {
@@ -107,26 +281,28 @@ impl Cache {
 				self.data[&self.profile]["manual"] = value.into();
 			}
 
-			// Just store the ID.
-			Mode::Desktop(id) => {
-				if self.data[&self.profile]["desktop"].is_null() {
-					self.data[&self.profile]["desktop"] = object!{};
+			// Just store the name or index.
+			Mode::Desktop(desktop) => {
+				let (section, key) = Cache::desktop(&desktop);
+
+				if self.data[&self.profile][section].is_null() {
+					self.data[&self.profile][section] = object!{};
 				}
 
-				self.data[&self.profile]["desktop"][id.to_string()] = value.into();
+				self.data[&self.profile][section][key] = value.into();
 			}
 
 			// Store both the WM_CLASS instance and class name.
 			Mode::Window(active) => {
-				if let Some(id) = active {
+				if let Some(id) = self.owner(active)? {
 					if self.data[&self.profile]["window"].is_null() {
 						self.data[&self.profile]["window"] = object!{};
 					}
 
-					let name = xcbu::icccm::get_wm_class(&self.display, id).get_reply()?;
+					let (instance, class) = self.class(id)?;
 
-					self.data[&self.profile]["window"][name.instance()] = value.into();
-					self.data[&self.profile]["window"][name.class()]    = value.into();
+					self.data[&self.profile]["window"][instance] = value.into();
+					self.data[&self.profile]["window"][class]    = value.into();
 				}
 			}
 
@@ -185,9 +361,11 @@ impl Cache {
 				}
 			}
 
-			// Desktop just checks the desktop ID.
-			Mode::Desktop(id) => {
-				if let Some(value) = self.data[&self.profile]["desktop"][id.to_string()].as_f32() {
+			// Desktop just checks the desktop name or index.
+			Mode::Desktop(desktop) => {
+				let (section, key) = Cache::desktop(&desktop);
+
+				if let Some(value) = self.data[&self.profile][section][key].as_f32() {
 					return Ok(Some(value))
 				}
 			}
@@ -199,14 +377,14 @@ impl Cache {
 			// the same class. (i.e. terminals using the same program but having
 			// different settings)
 			Mode::Window(active) => {
-				if let Some(id) = active {
-					let name = xcbu::icccm::get_wm_class(&self.display, id).get_reply()?;
+				if let Some(id) = self.owner(active)? {
+					let (instance, class) = self.class(id)?;
 
-					if let Some(value) = self.data[&self.profile]["window"][name.instance()].as_f32() {
+					if let Some(value) = self.data[&self.profile]["window"][instance].as_f32() {
 						return Ok(Some(value));
 					}
 
-					if let Some(value) = self.data[&self.profile]["window"][name.class()].as_f32() {
+					if let Some(value) = self.data[&self.profile]["window"][class].as_f32() {
 						return Ok(Some(value));
 					}
 				}
@@ -307,3 +485,32 @@ impl Drop for Cache {
 		self.save().unwrap();
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::fs;
+	use std::process;
+
+	#[test]
+	fn migrate() {
+		let path = env::temp_dir().join(format!("dux-cache-{}.json", process::id()));
+		fs::write(&path, r#"{ "default": { "desktop": { "0": 0.2, "1": 0.4 } }, "night": { "desktop": { "1": 0.1 } } }"#).unwrap();
+
+		let mut cache = Cache::open(None, Some(&path)).unwrap();
+		cache.migrate(&[Desktop { index: 1, name: Some("web".into()) }]);
+
+		// Every profile is migrated, not only the current one.
+		assert_eq!(cache.get(Mode::Desktop(Desktop { index: 1, name: Some("web".into()) })).unwrap(), Some(0.4));
+		assert_eq!(cache.get(Mode::Desktop(Desktop { index: 0, name: None })).unwrap(), Some(0.2));
+		assert!(cache.data["default"]["desktop"]["1"].is_null());
+
+		cache.profile("night");
+		assert_eq!(cache.get(Mode::Desktop(Desktop { index: 1, name: Some("web".into()) })).unwrap(), Some(0.1));
+		assert!(cache.data["night"]["desktop"]["1"].is_null());
+
+		drop(cache);
+		fs::remove_file(&path).ok();
+	}
+}
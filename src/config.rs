@@ -0,0 +1,546 @@
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// This file is part of dux.
+//
+// dux is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// dux is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with dux.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use json::{self, JsonValue};
+use clap::ArgMatches;
+
+use crate::{error, timer, interface, backlight, luminance, video, cache, idle};
+
+/// The daemon configuration.
+///
+/// It's loaded from a JSON file, any missing value falls back to the defaults
+/// and command line flags take precedence over it.
+#[derive(Clone, Debug)]
+pub struct Config {
+	path: PathBuf,
+
+	/// The backlight backend to use, the first available one if missing.
+	pub backend: Option<backlight::Backend>,
+
+	/// The mode to start the daemon with.
+	pub mode: interface::Mode,
+
+	/// The profile to start the daemon with.
+	pub profile: String,
+
+	/// The constant timers, in seconds.
+	pub timer: timer::Settings,
+
+	/// The adaptive brightness tunables.
+	pub adaptive: Adaptive,
+
+	/// The luminance computation settings.
+	pub luminance: Luminance,
+
+	/// Where the current desktop comes from.
+	pub desktop: Desktop,
+
+	/// How transient windows are handled in window mode.
+	pub transient: cache::Transient,
+
+	/// The size in bytes after which the history is rotated.
+	pub history: u64,
+
+	/// The video detection tunables.
+	pub video: video::Settings,
+
+	/// The idle dimming settings.
+	pub idle: idle::Settings,
+
+	/// The brightness to use while the session is locked, left alone if
+	/// missing.
+	pub lock: Option<f32>,
+
+	/// The settings for profiles without their own.
+	pub defaults: Profile,
+
+	/// The settings specific to each profile.
+	pub profiles: HashMap<String, Profile>,
+}
+
+/// The settings that can change between profiles.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+	/// How the luminance is smoothed in luminance mode.
+	pub smoothing: luminance::Smoothing,
+
+	/// What to do in luminance mode while the active window is fullscreen or
+	/// video is playing.
+	pub media: Policy,
+
+	/// The outputs that switch to this profile when they're all connected.
+	pub outputs: Vec<String>,
+}
+
+/// How the brightness is picked while watching media.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum Policy {
+	/// Keep following the luminance.
+	#[default]
+	Follow,
+
+	/// Keep the current brightness.
+	Freeze,
+
+	/// Use the given brightness.
+	Fixed(f32),
+
+	/// Use the brightness the active window has in window mode.
+	Window,
+}
+
+impl Policy {
+	pub fn parse<T: AsRef<str>>(value: T, brightness: f32) -> Option<Policy> {
+		match value.as_ref() {
+			"follow" => Some(Policy::Follow),
+			"freeze" => Some(Policy::Freeze),
+			"fixed"  => Some(Policy::Fixed(brightness)),
+			"window" => Some(Policy::Window),
+			_        => None,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Desktop {
+	/// The source of the current desktop.
+	pub source: Source,
+
+	/// The path to the IPC socket, looked up if missing.
+	pub socket: Option<PathBuf>,
+}
+
+/// Where the current desktop comes from.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum Source {
+	/// The `_NET_CURRENT_DESKTOP` and `_NET_DESKTOP_NAMES` properties.
+	#[default]
+	Ewmh,
+
+	/// The i3 IPC, also spoken by sway.
+	I3,
+}
+
+impl Source {
+	pub fn parse<T: AsRef<str>>(value: T) -> Option<Source> {
+		match value.as_ref() {
+			"ewmh" => Some(Source::Ewmh),
+			"i3"   => Some(Source::I3),
+			_      => None,
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Adaptive {
+	/// Distance in milliseconds within which damages are collapsed.
+	pub refresh: u64,
+
+	/// Minimum total number of pixels for damages to be collapsed.
+	pub threshold: u64,
+
+	/// Time to sleep between each fade step in milliseconds.
+	pub time: u64,
+
+	/// Step to increase the brightness by while fading.
+	pub step: f32,
+
+	/// Time in milliseconds after a manual brightness change during which the
+	/// luminance mode won't override it.
+	pub cooldown: u64,
+
+	/// Interval in milliseconds at which the screen is polled when DAMAGE is
+	/// not available.
+	pub poll: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Luminance {
+	/// How the luminance of a pixel is computed.
+	pub model: luminance::Model,
+
+	/// Which statistic of the pixel luminances is used.
+	pub metric: luminance::Metric,
+
+	/// How much each pixel counts towards the luminance.
+	pub weighting: luminance::Weighting,
+
+	/// Only every `stride` pixel on both axes is sampled.
+	pub stride: u32,
+
+	/// Whether the areas reserved by panels and docks are excluded.
+	pub struts: bool,
+
+	/// The WM_CLASS instance or class names of the windows to exclude.
+	pub exclude: Vec<String>,
+}
+
+impl Default for Luminance {
+	fn default() -> Self {
+		Luminance {
+			model:     luminance::Model::default(),
+			metric:    luminance::Metric::default(),
+			weighting: luminance::Weighting::default(),
+			stride:    1,
+			struts:    true,
+			exclude:   Vec::new(),
+		}
+	}
+}
+
+impl Default for Adaptive {
+	fn default() -> Self {
+		Adaptive {
+			refresh:   500,
+			threshold: 160_000,
+			time:      5,
+			step:      1.0,
+			cooldown:  1_000,
+			poll:      1_000,
+		}
+	}
+}
+
+impl Config {
+	/// Open the configuration at the given path.
+	pub fn open<T: AsRef<Path>>(path: Option<T>) -> error::Result<Self> {
+		// If no path was given we use the XDG standard places.
+		let path = if let Some(path) = path {
+			path.as_ref().into()
+		}
+		else {
+			let dirs = xdg::BaseDirectories::with_prefix("dux").unwrap();
+
+			// Only look for the file, the directory is left alone if missing.
+			dirs.find_config_file("config.json")
+				.unwrap_or_else(|| dirs.get_config_home().join("config.json"))
+		};
+
+		// Load the contents if the file exists.
+		let data = if path.exists() {
+			let mut file    = File::open(&path)?;
+			let mut content = String::new();
+			file.read_to_string(&mut content)?;
+
+			json::parse(&content)?
+		}
+		else {
+			JsonValue::Null
+		};
+
+		let defaults   = Profile::parse(&data, &Profile::default())?;
+		let video      = video::Settings::default();
+		let idle       = idle::Settings::default();
+		let adaptive   = Adaptive::default();
+		let luminance  = Luminance::default();
+		let rest       = data["luminance"]["rest"].as_f32().unwrap_or(0.25);
+		let percentile = data["luminance"]["percentile"].as_f32().unwrap_or(90.0) / 100.0;
+
+		let config = Config {
+			path,
+
+			backend: match data["backend"].as_str() {
+				Some(name) =>
+					Some(backlight::Backend::parse(name).ok_or_else(|| format!("unknown backend `{}`", name))?),
+
+				None =>
+					None,
+			},
+
+			mode: match data["mode"].as_str() {
+				Some(name) =>
+					interface::Mode::parse(name).ok_or_else(|| format!("unknown mode `{}`", name))?,
+
+				None =>
+					interface::Mode::default(),
+			},
+
+			profile: data["profile"].as_str().unwrap_or("default").into(),
+
+			timer: timer::Settings {
+				save:      data["timer"]["save"].as_u64().unwrap_or(30),
+				heartbeat: data["timer"]["heartbeat"].as_u64().unwrap_or(300),
+			},
+
+			adaptive: Adaptive {
+				refresh:   data["adaptive"]["refresh"].as_u64().unwrap_or(adaptive.refresh),
+				threshold: data["adaptive"]["threshold"].as_u64().unwrap_or(adaptive.threshold),
+				time:      data["adaptive"]["time"].as_u64().unwrap_or(adaptive.time),
+				step:      data["adaptive"]["step"].as_f32().unwrap_or(adaptive.step),
+				cooldown:  data["adaptive"]["cooldown"].as_u64().unwrap_or(adaptive.cooldown),
+				poll:      data["adaptive"]["poll"].as_u64().unwrap_or(adaptive.poll),
+			},
+
+			luminance: Luminance {
+				model: match data["luminance"]["model"].as_str() {
+					Some(name) =>
+						luminance::Model::parse(name).ok_or_else(|| format!("unknown model `{}`", name))?,
+
+					None =>
+						luminance::Model::default(),
+				},
+
+				metric: match data["luminance"]["metric"].as_str() {
+					Some(name) =>
+						luminance::Metric::parse(name, percentile).ok_or_else(|| format!("unknown metric `{}`", name))?,
+
+					None =>
+						luminance::Metric::default(),
+				},
+
+				weighting: match data["luminance"]["weighting"].as_str() {
+					Some(name) =>
+						luminance::Weighting::parse(name, rest).ok_or_else(|| format!("unknown weighting `{}`", name))?,
+
+					None =>
+						luminance::Weighting::default(),
+				},
+
+				stride: data["luminance"]["stride"].as_u32().unwrap_or(luminance.stride),
+				struts: data["luminance"]["struts"].as_bool().unwrap_or(luminance.struts),
+
+				exclude: data["luminance"]["exclude"].members()
+					.filter_map(|v| v.as_str().map(String::from))
+					.collect(),
+			},
+
+			desktop: Desktop {
+				source: match data["desktop"]["source"].as_str() {
+					Some(name) =>
+						Source::parse(name).ok_or_else(|| format!("unknown desktop source `{}`", name))?,
+
+					None =>
+						Source::default(),
+				},
+
+				socket: data["desktop"]["socket"].as_str().map(PathBuf::from),
+			},
+
+			transient: match data["window"]["transient"].as_str() {
+				Some(name) =>
+					cache::Transient::parse(name).ok_or_else(|| format!("unknown transient handling `{}`", name))?,
+
+				None =>
+					cache::Transient::default(),
+			},
+
+			history: data["history"]["limit"].as_u64().unwrap_or(1024 * 1024),
+
+			video: video::Settings {
+				rate: data["video"]["rate"].as_f32().unwrap_or(video.rate),
+				time: data["video"]["time"].as_u64().unwrap_or(video.time),
+				area: data["video"]["area"].as_f32().unwrap_or(video.area),
+			},
+
+			lock: data["lock"]["brightness"].as_f32(),
+
+			idle: idle::Settings {
+				dim:   data["idle"]["dim"].as_u64().unwrap_or(idle.dim),
+				level: data["idle"]["level"].as_f32().unwrap_or(idle.level),
+				off:   data["idle"]["off"].as_u64().unwrap_or(idle.off),
+			},
+
+			profiles: data["profiles"].entries()
+				.map(|(name, value)| Ok((name.to_owned(), Profile::parse(value, &defaults)?)))
+				.collect::<error::Result<_>>()?,
+
+			defaults,
+		};
+
+		config.validate()?;
+		Ok(config)
+	}
+
+	/// Make sure the numeric settings are within range.
+	fn validate(&self) -> error::Result<()> {
+		if self.timer.save == 0 {
+			return Err(error::Error::Message("`timer.save` must be greater than 0".into()));
+		}
+
+		if self.timer.heartbeat == 0 {
+			return Err(error::Error::Message("`timer.heartbeat` must be greater than 0".into()));
+		}
+
+		if !(self.adaptive.step > 0.0 && self.adaptive.step <= 100.0) {
+			return Err(error::Error::Message(format!("invalid step `{}`, must be between 0 and 100", self.adaptive.step)));
+		}
+
+		if let Some(value) = self.lock {
+			if !(0.0 ..= 100.0).contains(&value) {
+				return Err(error::Error::Message(format!("invalid lock brightness `{}`, must be between 0 and 100", value)));
+			}
+		}
+
+		if !(0.0 ..= 100.0).contains(&self.idle.level) {
+			return Err(error::Error::Message(format!("invalid idle level `{}`, must be between 0 and 100", self.idle.level)));
+		}
+
+		Ok(())
+	}
+
+	/// Get the settings for the given profile.
+	pub fn settings(&self, profile: &str) -> &Profile {
+		self.profiles.get(profile).unwrap_or(&self.defaults)
+	}
+
+	/// Get the profile to use with the given outputs connected, the first one
+	/// in alphabetical order whose outputs are all connected.
+	pub fn rule<T: AsRef<str>>(&self, connected: &[T]) -> Option<&str> {
+		let mut names = self.profiles.iter()
+			.filter(|(_, p)| !p.outputs.is_empty() && p.outputs.iter().all(|o| connected.iter().any(|c| c.as_ref() == o)))
+			.map(|(name, _)| name.as_ref())
+			.collect::<Vec<_>>();
+
+		names.sort();
+		names.first().cloned()
+	}
+
+	/// Reload the configuration from the same path.
+	pub fn reload(&self) -> error::Result<Self> {
+		Config::open(Some(&self.path))
+	}
+
+	/// Override the configuration with the given command line flags.
+	pub fn merge(&mut self, matches: &ArgMatches) -> error::Result<()> {
+		macro_rules! flag {
+			($name:expr) => (
+				if let Some(value) = matches.value_of($name) {
+					Some(value.parse().map_err(|_| format!("invalid value for `--{}`", $name))?)
+				}
+				else {
+					None
+				}
+			)
+		}
+
+		if let Some(name) = matches.value_of("backend") {
+			self.backend = Some(backlight::Backend::parse(name).ok_or_else(|| format!("unknown backend `{}`", name))?);
+		}
+
+		if let Some(name) = matches.value_of("mode") {
+			self.mode = interface::Mode::parse(name).ok_or_else(|| format!("unknown mode `{}`", name))?;
+		}
+
+		if let Some(name) = matches.value_of("profile") {
+			self.profile = name.into();
+		}
+
+		if let Some(name) = matches.value_of("metric") {
+			let percentile = if let luminance::Metric::Percentile(p) = self.luminance.metric { p } else { 0.9 };
+			self.luminance.metric = luminance::Metric::parse(name, percentile).ok_or_else(|| format!("unknown metric `{}`", name))?;
+		}
+
+		if let Some(name) = matches.value_of("weighting") {
+			let rest = if let luminance::Weighting::Focus(rest) = self.luminance.weighting { rest } else { 0.25 };
+			self.luminance.weighting = luminance::Weighting::parse(name, rest).ok_or_else(|| format!("unknown weighting `{}`", name))?;
+		}
+
+		if let Some(value) = flag!("refresh") {
+			self.adaptive.refresh = value;
+		}
+
+		if let Some(value) = flag!("threshold") {
+			self.adaptive.threshold = value;
+		}
+
+		if let Some(value) = flag!("cooldown") {
+			self.adaptive.cooldown = value;
+		}
+
+		if let Some(value) = flag!("poll") {
+			self.adaptive.poll = value;
+		}
+
+		if let Some(value) = flag!("time") {
+			self.adaptive.time = value;
+		}
+
+		if let Some(value) = flag!("step") {
+			self.adaptive.step = value;
+		}
+
+		self.validate()
+	}
+}
+
+impl Profile {
+	/// Parse the profile settings, any missing value is taken from `defaults`.
+	fn parse(data: &JsonValue, defaults: &Profile) -> error::Result<Profile> {
+		let smoothing = &data["smoothing"];
+		let media     = &data["media"];
+
+		Ok(Profile {
+			smoothing: luminance::Smoothing {
+				rise: smoothing["rise"].as_u64().unwrap_or(defaults.smoothing.rise),
+				fall: smoothing["fall"].as_u64().unwrap_or(defaults.smoothing.fall),
+				band: smoothing["band"].as_f32().unwrap_or(defaults.smoothing.band),
+			},
+
+			media: match media["policy"].as_str() {
+				Some(name) =>
+					Policy::parse(name, media["brightness"].as_f32().unwrap_or(50.0))
+						.ok_or_else(|| format!("unknown media policy `{}`", name))?,
+
+				None =>
+					defaults.media,
+			},
+
+			outputs: data["outputs"].members()
+				.filter_map(|v| v.as_str().map(String::from))
+				.collect(),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::fs;
+	use std::process;
+
+	fn open(name: &str, content: &str) -> error::Result<Config> {
+		let path = env::temp_dir().join(format!("dux-config-{}-{}.json", name, process::id()));
+		fs::write(&path, content).unwrap();
+
+		let config = Config::open(Some(&path));
+		fs::remove_file(&path).ok();
+
+		config
+	}
+
+	#[test]
+	fn numbers() {
+		let config = open("valid", r#"{ "timer": { "save": 10 }, "adaptive": { "step": 2.5 }, "lock": { "brightness": 0 }, "idle": { "level": 100 } }"#).unwrap();
+		assert_eq!(config.timer.save, 10);
+		assert_eq!(config.adaptive.step, 2.5);
+		assert_eq!(config.lock, Some(0.0));
+		assert_eq!(config.idle.level, 100.0);
+
+		assert!(open("save", r#"{ "timer": { "save": 0 } }"#).is_err());
+		assert!(open("heartbeat", r#"{ "timer": { "heartbeat": 0 } }"#).is_err());
+		assert!(open("step", r#"{ "adaptive": { "step": -1 } }"#).is_err());
+		assert!(open("zero", r#"{ "adaptive": { "step": 0 } }"#).is_err());
+		assert!(open("lock", r#"{ "lock": { "brightness": 150 } }"#).is_err());
+		assert!(open("level", r#"{ "idle": { "level": -5 } }"#).is_err());
+	}
+}
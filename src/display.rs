@@ -24,17 +24,74 @@ use crate::error;
 
 /// Handles the X11 display.
 pub struct Display {
-	connection: xcbu::ewmh::Connection,
-	screen:     i32,
-	root:       xcb::Window,
+	connection:   xcbu::ewmh::Connection,
+	screen:       i32,
+	root:         xcb::Window,
+	capabilities: Capabilities,
+}
+
+/// The optional extensions supported by the display.
+#[derive(Copy, Clone, Debug)]
+pub struct Capabilities {
+	/// MIT-SHM is available, otherwise screen contents are fetched with plain
+	/// `GetImage` requests.
+	pub shm: bool,
+
+	/// DAMAGE and XFixes are available, otherwise screen contents have to be
+	/// polled.
+	pub damage: bool,
+
+	/// MIT-SCREEN-SAVER is available, otherwise the user idle time is unknown.
+	pub screensaver: bool,
+}
+
+/// The pixel layout of the root window.
+#[derive(Copy, Clone, Debug)]
+pub struct Visual {
+	/// The number of significant bits in a pixel.
+	pub depth: u8,
+
+	/// The number of bits a pixel takes in an image.
+	pub bpp: u8,
+
+	/// The masks of each channel within a pixel.
+	pub red:   u32,
+	pub green: u32,
+	pub blue:  u32,
+
+	/// Whether pixels are stored most significant byte first.
+	pub msb: bool,
+}
+
+/// A connected and enabled monitor.
+#[derive(Clone, Debug)]
+pub struct Output {
+	pub id:   xcb::randr::Output,
+	pub name: String,
+
+	pub x:      u32,
+	pub y:      u32,
+	pub width:  u32,
+	pub height: u32,
+}
+
+impl Output {
+	/// Check if the output is a builtin panel, going by the usual connector
+	/// names.
+	pub fn is_panel(&self) -> bool {
+		self.name.starts_with("eDP") || self.name.starts_with("LVDS") || self.name.starts_with("DSI")
+	}
 }
 
 impl Display {
-	/// Open the default display.
-	pub fn open() -> error::Result<Self> {
-		let (connection, screen) = xcb::Connection::connect(None)?;
-		let connection           = xcbu::ewmh::Connection::connect(connection).map_err(|(e, _)| e)?;
-		let root                 = connection.get_setup().roots().nth(screen as usize).unwrap().root();
+	/// Open the given display, or the one in `DISPLAY`, on the given screen or
+	/// its default one.
+	pub fn open(name: Option<&str>, screen: Option<i32>) -> error::Result<Self> {
+		let (connection, default) = xcb::Connection::connect(name)?;
+		let connection            = xcbu::ewmh::Connection::connect(connection).map_err(|(e, _)| e)?;
+		let screen                = screen.unwrap_or(default);
+		let root                  = connection.get_setup().roots().nth(screen as usize)
+			.ok_or_else(|| format!("screen {} not found", screen))?.root();
 
 		// Randr is used for the backlight and screen configuration changes events.
 		{
@@ -44,51 +101,125 @@ impl Display {
 				return Err(error::Error::Unsupported);
 			}
 
-			xcb::randr::select_input_checked(&connection, root, xcb::randr::NOTIFY_MASK_SCREEN_CHANGE as u16)
-				.request_check()?;
-		}
-
-		// MIT-SHM is used to fetch screen contents.
-		{
-			let version = xcb::shm::query_version(&connection).get_reply()?;
-
-			if version.major_version() != 1 || version.minor_version() < 1 {
-				return Err(error::Error::Unsupported);
-			}
+			xcb::randr::select_input_checked(&connection, root, (xcb::randr::NOTIFY_MASK_SCREEN_CHANGE |
+				xcb::randr::NOTIFY_MASK_OUTPUT_CHANGE | xcb::randr::NOTIFY_MASK_CRTC_CHANGE) as u16)
+					.request_check()?;
 		}
 
-		// DAMAGE is used to get screen content changes.
-		{
-			let version  = xcb::damage::query_version(&connection, 1, 1).get_reply()?;
-
-			if version.major_version() != 1 || version.minor_version() < 1 {
-				return Err(error::Error::Unsupported);
-			}
-		}
+		// MIT-SHM is used to fetch screen contents, it's not available on remote
+		// connections so it's optional.
+		let shm = connection.get_extension_data(xcb::shm::id()).map(|e| e.present()).unwrap_or(false) &&
+			xcb::shm::query_version(&connection).get_reply().ok()
+				.map(|v| v.major_version() == 1 && v.minor_version() >= 1).unwrap_or(false);
+
+		// DAMAGE is used to get screen content changes, some minimal servers don't
+		// have it so it's optional as well.
+		//
+		// The damaged areas are fetched as XFixes regions, which DAMAGE depends
+		// on anyway.
+		let damage = connection.get_extension_data(xcb::damage::id()).map(|e| e.present()).unwrap_or(false) &&
+			xcb::xfixes::query_version(&connection, 2, 0).get_reply().ok()
+				.map(|v| v.major_version() >= 2).unwrap_or(false) &&
+			xcb::damage::query_version(&connection, 1, 1).get_reply().ok()
+				.map(|v| v.major_version() == 1 && v.minor_version() >= 1).unwrap_or(false);
+
+		// MIT-SCREEN-SAVER is used to know how long the user has been idle.
+		let screensaver = connection.get_extension_data(xcb::screensaver::id()).map(|e| e.present()).unwrap_or(false);
+
+		let capabilities = Capabilities { shm, damage, screensaver };
+
+		Ok(Display { connection, screen, root, capabilities })
+	}
 
-		Ok(Display { connection, screen, root })
+	/// Get the optional extensions supported by the display.
+	pub fn capabilities(&self) -> Capabilities {
+		self.capabilities
 	}
 
-	/// Get the default screen.
+	/// Get the screen in use.
 	pub fn screen(&self) -> i32 {
 		self.screen
 	}
 
-	/// Get the root window for the default screen.
+	/// Get the number of screens on the display.
+	pub fn screens(&self) -> i32 {
+		self.get_setup().roots().count() as i32
+	}
+
+	/// Get the root window for the screen in use.
 	pub fn root(&self) -> xcb::Window {
 		self.root
 	}
 
-	/// Get the default screen width.
+	/// Get the screen width.
 	pub fn width(&self) -> u32 {
 		u32::from(self.get_setup().roots().nth(self.screen as usize).unwrap().width_in_pixels())
 	}
 
-	/// Get the default screen height.
+	/// Get the screen height.
 	pub fn height(&self) -> u32 {
 		u32::from(self.get_setup().roots().nth(self.screen as usize).unwrap().height_in_pixels())
 	}
 
+	/// Get the pixel layout of the root window.
+	pub fn visual(&self) -> error::Result<Visual> {
+		let setup  = self.get_setup();
+		let screen = setup.roots().nth(self.screen as usize).unwrap();
+
+		for depth in screen.allowed_depths() {
+			for visual in depth.visuals() {
+				if visual.visual_id() != screen.root_visual() {
+					continue;
+				}
+
+				let format = setup.pixmap_formats().find(|f| f.depth() == depth.depth())
+					.ok_or(error::Error::Unsupported)?;
+
+				return Ok(Visual {
+					depth: depth.depth(),
+					bpp:   format.bits_per_pixel(),
+
+					red:   visual.red_mask(),
+					green: visual.green_mask(),
+					blue:  visual.blue_mask(),
+
+					msb: u32::from(setup.image_byte_order()) == xcb::IMAGE_ORDER_MSB_FIRST,
+				});
+			}
+		}
+
+		Err(error::Error::Unsupported)
+	}
+
+	/// Get the connected and enabled outputs on the screen.
+	pub fn outputs(&self) -> error::Result<Vec<Output>> {
+		let resources = xcb::randr::get_screen_resources_current(self, self.root).get_reply()?;
+		let mut result = Vec::new();
+
+		for &id in resources.outputs() {
+			let info = xcb::randr::get_output_info(self, id, resources.config_timestamp()).get_reply()?;
+
+			// Skip disconnected or disabled outputs.
+			if info.connection() != xcb::randr::CONNECTION_CONNECTED as u8 || info.crtc() == xcb::NONE {
+				continue;
+			}
+
+			let crtc = xcb::randr::get_crtc_info(self, info.crtc(), resources.config_timestamp()).get_reply()?;
+
+			result.push(Output {
+				id,
+				name: String::from_utf8_lossy(info.name()).into_owned(),
+
+				x:      crtc.x() as u32,
+				y:      crtc.y() as u32,
+				width:  u32::from(crtc.width()),
+				height: u32::from(crtc.height()),
+			});
+		}
+
+		Ok(result)
+	}
+
 	/// Get the XRandr extension details.
 	pub fn randr(&self) -> xcb::QueryExtensionData {
 		self.connection.get_extension_data(xcb::randr::id()).unwrap()
@@ -0,0 +1,150 @@
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// This file is part of dux.
+//
+// dux is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// dux is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with dux.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+
+use crate::{Display, Screen, Observer, Backlight, Config, error, backlight, luminance, cache, video};
+
+/// The state the adaptive daemon keeps for every X screen it manages.
+pub struct Head {
+	pub primary:    bool,
+	pub display:    Arc<Display>,
+	pub screen:     Screen,
+	pub backlights: Vec<Box<dyn Backlight>>,
+	pub brightness: Vec<f32>,
+	pub smoothers:  Vec<luminance::Smoother>,
+	pub active:     Option<xcb::Window>,
+	pub desktop:    cache::Desktop,
+	pub fullscreen: bool,
+	pub detector:   video::Detector,
+	pub struts:     Vec<xcb::Rectangle>,
+}
+
+impl Head {
+	/// Open the given screen.
+	///
+	/// Only the first screen gets backlights that aren't tied to an output,
+	/// since the kernel ones would be shared by every screen otherwise.
+	pub fn open(display: Arc<Display>, config: &Config, primary: bool) -> error::Result<Self> {
+		let mut screen = Screen::open(display.clone(), display.width(), display.height())?;
+		screen.stride(config.luminance.stride)?;
+		screen.model(config.luminance.model)?;
+		screen.metric(config.luminance.metric);
+		screen.weighting(config.luminance.weighting);
+
+		let mut head = Head {
+			primary, display, screen,
+			backlights: Vec::new(),
+			brightness: Vec::new(),
+			smoothers:  Vec::new(),
+			active:     None,
+			desktop:    cache::Desktop::default(),
+			fullscreen: false,
+			detector:   video::Detector::new(config.video),
+			struts:     Vec::new(),
+		};
+
+		head.backlights(config)?;
+
+		Ok(head)
+	}
+
+	/// Open the backlights again, the state of the ones that are still there is
+	/// kept.
+	pub fn backlights(&mut self, config: &Config) -> error::Result<()> {
+		let primary = self.primary;
+
+		// A screen can have no backlight at all, until a panel is connected.
+		let found = match backlight::all(self.display.clone(), config.backend) {
+			Ok(value) =>
+				value.into_iter().filter(|b| primary || b.output().is_some()).collect::<Vec<_>>(),
+
+			Err(error::Error::Unsupported) =>
+				Vec::new(),
+
+			Err(err) =>
+				return Err(err),
+		};
+
+		let mut brightness = Vec::with_capacity(found.len());
+		let mut smoothers  = Vec::with_capacity(found.len());
+
+		for light in &found {
+			match self.backlights.iter().position(|b| b.output().is_some() && b.output() == light.output()) {
+				Some(index) => {
+					brightness.push(self.brightness[index]);
+					smoothers.push(self.smoothers[index]);
+				}
+
+				None => {
+					brightness.push(0.0);
+					smoothers.push(luminance::Smoother::default());
+				}
+			}
+		}
+
+		self.brightness = brightness;
+		self.smoothers  = smoothers;
+		self.backlights = found;
+
+		Ok(())
+	}
+
+	/// Pick up connected, disconnected or reconfigured outputs.
+	pub fn rediscover(&mut self, config: &Config) -> error::Result<()> {
+		self.screen.layout()?;
+		self.backlights(config)
+	}
+
+	/// Apply the luminance settings.
+	pub fn configure(&mut self, config: &Config) -> error::Result<()> {
+		self.detector.update(config.video);
+		self.screen.stride(config.luminance.stride)?;
+		self.screen.model(config.luminance.model)?;
+		self.screen.metric(config.luminance.metric);
+		self.screen.weighting(config.luminance.weighting);
+
+		Ok(())
+	}
+
+	/// Exclude panels, docks and the configured windows from the luminance,
+	/// returns whether anything changed.
+	pub fn exclude(&mut self, config: &Config) -> bool {
+		let mut areas = Vec::new();
+
+		if config.luminance.struts {
+			areas.extend(self.struts.iter().cloned());
+		}
+
+		if !config.luminance.exclude.is_empty() {
+			areas.extend(Observer::matching(&self.display, &config.luminance.exclude).unwrap_or_default());
+		}
+
+		self.screen.exclude(areas)
+	}
+
+	/// Focus the luminance on the active window.
+	pub fn focus(&mut self) {
+		let area = self.active.and_then(|id| Observer::geometry(&self.display, id).ok());
+		self.screen.focus(area);
+	}
+
+	/// Get the name of the given output.
+	pub fn output(&self, id: Option<xcb::randr::Output>) -> Option<&str> {
+		id.and_then(|id| self.screen.output(id)).map(|o| o.name.as_ref())
+	}
+}
@@ -0,0 +1,236 @@
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// This file is part of dux.
+//
+// dux is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// dux is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with dux.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Write, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use json::{self, JsonValue, object};
+
+use crate::error;
+
+/// An append-only journal of brightness changes.
+///
+/// Every change is written as a single JSON object per line, when the file
+/// grows past the size limit it's rotated to a `.1` file, so at most two
+/// files are ever kept around.
+pub struct History {
+	path:  PathBuf,
+	limit: u64,
+}
+
+/// What caused a brightness change.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Cause {
+	/// The user changed the brightness through the CLI or DBus.
+	User,
+
+	/// The adaption mode was changed.
+	Mode,
+
+	/// The profile was switched.
+	Profile,
+
+	/// The active mode faded to a new value.
+	Fade,
+}
+
+impl Cause {
+	pub fn parse<T: AsRef<str>>(value: T) -> Option<Cause> {
+		match value.as_ref() {
+			"user"    => Some(Cause::User),
+			"mode"    => Some(Cause::Mode),
+			"profile" => Some(Cause::Profile),
+			"fade"    => Some(Cause::Fade),
+			_         => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Cause::User    => "user",
+			Cause::Mode    => "mode",
+			Cause::Profile => "profile",
+			Cause::Fade    => "fade",
+		}
+	}
+}
+
+impl History {
+	/// Get the path to the journal, using the XDG standard places if none was
+	/// given.
+	pub fn path<T: AsRef<Path>>(path: Option<T>) -> PathBuf {
+		if let Some(path) = path {
+			path.as_ref().into()
+		}
+		else {
+			xdg::BaseDirectories::with_prefix("dux").unwrap()
+				.place_data_file("history.jsonl").unwrap()
+		}
+	}
+
+	/// Open the journal at the given path, rotating it when it grows bigger than
+	/// `limit` bytes.
+	pub fn open<T: AsRef<Path>>(path: Option<T>, limit: u64) -> error::Result<Self> {
+		Ok(History {
+			path: History::path(path),
+			limit,
+		})
+	}
+
+	/// Append a brightness change to the journal.
+	pub fn record(&mut self, cause: Cause, profile: &str, output: Option<&str>, (mode, key): (&str, JsonValue), value: f32) -> error::Result<()> {
+		let line = object!{
+			"time"    => chrono::Local::now().to_rfc3339(),
+			"cause"   => cause.name(),
+			"profile" => profile,
+			"output"  => output,
+			"mode"    => mode,
+			"key"     => key,
+			"value"   => value,
+		}.dump();
+
+		// Rotate the journal if the new line would make it go over the limit.
+		if let Ok(metadata) = fs::metadata(&self.path) {
+			if metadata.len() + line.len() as u64 + 1 > self.limit {
+				fs::rename(&self.path, History::rotated(&self.path))?;
+			}
+		}
+
+		let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+		writeln!(&mut file, "{}", line)?;
+
+		Ok(())
+	}
+
+	/// Read all the entries in the journal, oldest first.
+	pub fn entries<T: AsRef<Path>>(path: T) -> error::Result<Vec<JsonValue>> {
+		let mut entries = Vec::new();
+
+		for path in &[History::rotated(path.as_ref()), path.as_ref().into()] {
+			if !path.exists() {
+				continue;
+			}
+
+			for line in BufReader::new(File::open(path)?).lines() {
+				// Skip any line that got mangled, the journal is only informative.
+				if let Ok(entry) = json::parse(&line?) {
+					entries.push(entry);
+				}
+			}
+		}
+
+		Ok(entries)
+	}
+
+	/// Keep the most recent `count` entries with the given cause and mode, or
+	/// all of them if `count` is 0.
+	pub fn filter<'a>(entries: &'a [JsonValue], cause: Option<Cause>, mode: Option<&str>, count: usize) -> Vec<&'a JsonValue> {
+		let entries = entries.iter()
+			.filter(|e| if let Some(c) = cause { e["cause"] == c.name() } else { true })
+			.filter(|e| if let Some(m) = mode { e["mode"] == m } else { true })
+			.collect::<Vec<_>>();
+
+		let skip = if count == 0 { 0 } else { entries.len().saturating_sub(count) };
+
+		entries[skip ..].to_vec()
+	}
+
+	fn rotated(path: &Path) -> PathBuf {
+		let mut name = path.file_name().unwrap_or_default().to_os_string();
+		name.push(".1");
+
+		path.with_file_name(name)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::process;
+
+	fn journal(name: &str) -> PathBuf {
+		let path = env::temp_dir().join(format!("dux-history-{}-{}.jsonl", name, process::id()));
+		fs::remove_file(&path).ok();
+		fs::remove_file(History::rotated(&path)).ok();
+
+		path
+	}
+
+	#[test]
+	fn rotate() {
+		let path        = journal("rotate");
+		let mut history = History::open(Some(&path), 1_000).unwrap();
+
+		for value in 0 .. 20 {
+			history.record(Cause::Fade, "default", Some("eDP-1"), ("luminance", 0.5.into()), value as f32).unwrap();
+		}
+
+		// Neither file ever goes over the limit.
+		let current = fs::metadata(&path).unwrap().len();
+		let rotated = fs::metadata(History::rotated(&path)).unwrap().len();
+		assert!(current <= 1_000 && rotated <= 1_000);
+
+		// The entries span both files, oldest first and without gaps, and only
+		// the oldest ones are gone.
+		let entries = History::entries(&path).unwrap();
+		let values  = entries.iter().map(|e| e["value"].as_f32().unwrap()).collect::<Vec<_>>();
+		assert!(entries.len() < 20 && entries.len() > 10);
+		assert_eq!(values, (20 - entries.len() .. 20).map(|v| v as f32).collect::<Vec<_>>());
+		assert_eq!(entries[0]["output"], "eDP-1");
+
+		fs::remove_file(&path).ok();
+		fs::remove_file(History::rotated(&path)).ok();
+	}
+
+	#[test]
+	fn mangled() {
+		let path = journal("mangled");
+		fs::write(History::rotated(&path), "{\"value\":1}\nnope\n").unwrap();
+		fs::write(&path, "{\"value\":2}\n").unwrap();
+
+		let entries = History::entries(&path).unwrap();
+		assert_eq!(entries.iter().map(|e| e["value"].as_i32().unwrap()).collect::<Vec<_>>(), [1, 2]);
+
+		fs::remove_file(&path).ok();
+		fs::remove_file(History::rotated(&path)).ok();
+	}
+
+	#[test]
+	fn filter() {
+		let entries = vec![
+			object!{ "cause" => "user", "mode" => "luminance", "value" => 1 },
+			object!{ "cause" => "fade", "mode" => "luminance", "value" => 2 },
+			object!{ "cause" => "fade", "mode" => "window", "value" => 3 },
+			object!{ "cause" => "mode", "mode" => "window", "value" => 4 },
+			object!{ "cause" => "fade", "mode" => "luminance", "value" => 5 },
+		];
+
+		let values = |cause, mode, count| History::filter(&entries, cause, mode, count).iter()
+			.map(|e| e["value"].as_i32().unwrap()).collect::<Vec<_>>();
+
+		assert_eq!(values(None, None, 0), [1, 2, 3, 4, 5]);
+		assert_eq!(values(Some(Cause::Fade), None, 0), [2, 3, 5]);
+		assert_eq!(values(None, Some("window"), 0), [3, 4]);
+		assert_eq!(values(Some(Cause::Fade), Some("luminance"), 0), [2, 5]);
+
+		// The count keeps the most recent ones after filtering.
+		assert_eq!(values(Some(Cause::Fade), None, 2), [3, 5]);
+		assert_eq!(values(None, None, 10), [1, 2, 3, 4, 5]);
+	}
+}
@@ -0,0 +1,328 @@
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// This file is part of dux.
+//
+// dux is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// dux is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with dux.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+use std::thread;
+use std::process::Command;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::ops::Deref;
+
+use json::{self, JsonValue};
+use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+use channel::{self, Receiver};
+
+use crate::{error, cache};
+
+const MAGIC: &[u8] = b"i3-ipc";
+
+const GET_WORKSPACES: u32 = 1;
+const SUBSCRIBE:      u32 = 2;
+
+/// Events have the highest bit set.
+const EVENT:     u32 = 1 << 31;
+const WORKSPACE: u32 = EVENT;
+
+/// Tracks the workspaces of i3, sway or any window manager speaking the same
+/// IPC protocol.
+pub struct I3 {
+	receiver: Receiver<Event>,
+}
+
+#[derive(Debug)]
+pub enum Event {
+	/// The workspaces changed.
+	Workspaces(Vec<Workspace>),
+
+	/// The connection to the window manager has been lost.
+	Closed(error::Error),
+}
+
+/// A workspace as reported by the window manager.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Workspace {
+	pub num:     i32,
+	pub name:    String,
+	pub output:  String,
+	pub focused: bool,
+	pub visible: bool,
+}
+
+impl Workspace {
+	/// Get the desktop it stands for.
+	pub fn desktop(&self) -> cache::Desktop {
+		cache::Desktop {
+			index: self.num,
+			name:  Some(self.name.clone()),
+		}
+	}
+}
+
+/// A connection to the IPC socket.
+pub struct Connection {
+	stream: UnixStream,
+}
+
+impl Connection {
+	/// Find the IPC socket path, from the environment or asking i3 itself.
+	pub fn socket() -> Option<PathBuf> {
+		if let Some(path) = env::var_os("I3SOCK").or_else(|| env::var_os("SWAYSOCK")) {
+			return Some(path.into());
+		}
+
+		let output = Command::new("i3").arg("--get-socketpath").output().ok()?;
+		let path   = String::from_utf8(output.stdout).ok()?;
+		let path   = path.trim();
+
+		if output.status.success() && !path.is_empty() {
+			Some(path.into())
+		}
+		else {
+			None
+		}
+	}
+
+	/// Connect to the given socket.
+	pub fn open<T: AsRef<Path>>(path: T) -> error::Result<Self> {
+		Ok(Connection {
+			stream: UnixStream::connect(path)?,
+		})
+	}
+
+	/// Send a message.
+	fn send(&mut self, kind: u32, payload: &str) -> error::Result<()> {
+		let mut message = Vec::with_capacity(MAGIC.len() + 8 + payload.len());
+		message.extend_from_slice(MAGIC);
+		message.write_u32::<NativeEndian>(payload.len() as u32)?;
+		message.write_u32::<NativeEndian>(kind)?;
+		message.extend_from_slice(payload.as_bytes());
+
+		self.stream.write_all(&message)?;
+
+		Ok(())
+	}
+
+	/// Receive the next message, either a reply or an event.
+	pub fn receive(&mut self) -> error::Result<(u32, JsonValue)> {
+		let mut magic = [0; 6];
+		self.stream.read_exact(&mut magic)?;
+
+		if magic != MAGIC {
+			return Err(error::Error::Message("invalid i3 IPC message".into()));
+		}
+
+		let length = self.stream.read_u32::<NativeEndian>()?;
+		let kind   = self.stream.read_u32::<NativeEndian>()?;
+
+		let mut payload = vec![0; length as usize];
+		self.stream.read_exact(&mut payload)?;
+
+		Ok((kind, json::parse(&String::from_utf8_lossy(&payload))?))
+	}
+
+	/// Send a request and wait for its reply, any event coming in between is
+	/// dropped.
+	fn request(&mut self, kind: u32, payload: &str) -> error::Result<JsonValue> {
+		self.send(kind, payload)?;
+
+		loop {
+			match self.receive()? {
+				(reply, value) if reply == kind =>
+					return Ok(value),
+
+				_ => ()
+			}
+		}
+	}
+
+	/// Get the current workspaces.
+	pub fn workspaces(&mut self) -> error::Result<Vec<Workspace>> {
+		Ok(self.request(GET_WORKSPACES, "")?.members().map(|w| Workspace {
+			num:     w["num"].as_i32().unwrap_or(-1),
+			name:    w["name"].as_str().unwrap_or("").into(),
+			output:  w["output"].as_str().unwrap_or("").into(),
+			focused: w["focused"].as_bool().unwrap_or(false),
+			visible: w["visible"].as_bool().unwrap_or(false),
+		}).collect())
+	}
+
+	/// Subscribe to the given events.
+	pub fn subscribe(&mut self, events: &[&str]) -> error::Result<()> {
+		let reply = self.request(SUBSCRIBE, &json::stringify(events.to_vec()))?;
+
+		if reply["success"].as_bool().unwrap_or(false) {
+			Ok(())
+		}
+		else {
+			Err(error::Error::Message("i3 IPC subscription failed".into()))
+		}
+	}
+}
+
+impl I3 {
+	/// Spawn the thread following the workspaces, the socket is looked up if
+	/// no path is given.
+	pub fn spawn(path: Option<PathBuf>) -> error::Result<Self> {
+		let path = path.or_else(Connection::socket)
+			.ok_or_else(|| error::Error::Message("i3 IPC socket not found".into()))?;
+
+		// Events have the high bit of their type set, but on the subscribed
+		// connection the GET_WORKSPACES replies would be interleaved with them,
+		// so one connection is used for the queries and one for the events.
+		let mut queries = Connection::open(&path)?;
+		let mut events  = Connection::open(&path)?;
+		events.subscribe(&["workspace"])?;
+
+		let (sender, receiver) = channel::unbounded();
+		sender.send(Event::Workspaces(queries.workspaces()?)).unwrap();
+
+		thread::spawn(move || {
+			let result = (|| -> error::Result<()> {
+				loop {
+					if let (WORKSPACE, _) = events.receive()? {
+						if sender.send(Event::Workspaces(queries.workspaces()?)).is_err() {
+							return Ok(());
+						}
+					}
+				}
+			})();
+
+			if let Err(err) = result {
+				sender.send(Event::Closed(err)).ok();
+			}
+		});
+
+		Ok(I3 { receiver })
+	}
+
+	/// A source that never sends anything, for when the IPC is not used.
+	pub fn none() -> Self {
+		I3 {
+			receiver: channel::never(),
+		}
+	}
+}
+
+impl Deref for I3 {
+	type Target = Receiver<Event>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.receiver
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs;
+	use std::process;
+	use std::os::unix::net::UnixListener;
+
+	/// Read a request from the client side of the fake server.
+	fn request(stream: &mut UnixStream) -> (u32, String) {
+		let mut magic = [0; 6];
+		stream.read_exact(&mut magic).unwrap();
+		assert_eq!(&magic, MAGIC);
+
+		let length = stream.read_u32::<NativeEndian>().unwrap();
+		let kind   = stream.read_u32::<NativeEndian>().unwrap();
+
+		let mut payload = vec![0; length as usize];
+		stream.read_exact(&mut payload).unwrap();
+
+		(kind, String::from_utf8(payload).unwrap())
+	}
+
+	/// Send a reply or event from the fake server.
+	fn reply(stream: &mut UnixStream, kind: u32, payload: &str) {
+		stream.write_all(MAGIC).unwrap();
+		stream.write_u32::<NativeEndian>(payload.len() as u32).unwrap();
+		stream.write_u32::<NativeEndian>(kind).unwrap();
+		stream.write_all(payload.as_bytes()).unwrap();
+	}
+
+	const FIRST: &str = r#"[
+		{ "num": 1, "name": "1: web", "output": "eDP-1", "focused": true, "visible": true },
+		{ "num": 2, "name": "2: code", "output": "HDMI-1", "focused": false, "visible": true }
+	]"#;
+
+	const SECOND: &str = r#"[
+		{ "num": 1, "name": "1: web", "output": "eDP-1", "focused": false, "visible": false },
+		{ "num": 2, "name": "2: code", "output": "HDMI-1", "focused": false, "visible": true },
+		{ "num": 3, "name": "mail", "output": "eDP-1", "focused": true, "visible": true }
+	]"#;
+
+	#[test]
+	fn workspaces() {
+		let path = env::temp_dir().join(format!("dux-i3-{}.sock", process::id()));
+		fs::remove_file(&path).ok();
+
+		let listener = UnixListener::bind(&path).unwrap();
+		let server   = thread::spawn(move || {
+			let (mut queries, _) = listener.accept().unwrap();
+			let (mut events, _)  = listener.accept().unwrap();
+
+			assert_eq!(request(&mut events), (SUBSCRIBE, r#"["workspace"]"#.into()));
+			reply(&mut events, SUBSCRIBE, r#"{ "success": true }"#);
+
+			assert_eq!(request(&mut queries), (GET_WORKSPACES, "".into()));
+			reply(&mut queries, GET_WORKSPACES, FIRST);
+
+			// Events that aren't about workspaces are ignored.
+			reply(&mut events, EVENT | 3, r#"{ "change": "focus" }"#);
+			reply(&mut events, WORKSPACE, r#"{ "change": "focus" }"#);
+
+			assert_eq!(request(&mut queries), (GET_WORKSPACES, "".into()));
+			reply(&mut queries, GET_WORKSPACES, SECOND);
+		});
+
+		let i3 = I3::spawn(Some(path.clone())).unwrap();
+
+		match i3.recv().unwrap() {
+			Event::Workspaces(list) => {
+				assert_eq!(list.len(), 2);
+				assert_eq!(list[0], Workspace { num: 1, name: "1: web".into(), output: "eDP-1".into(), focused: true, visible: true });
+				assert_eq!(list[1].output, "HDMI-1");
+			}
+
+			event => panic!("unexpected {:?}", event),
+		}
+
+		match i3.recv().unwrap() {
+			Event::Workspaces(list) => {
+				let focused = list.iter().find(|w| w.focused).unwrap().desktop();
+
+				assert_eq!(list.len(), 3);
+				assert_eq!(focused.index, 3);
+				assert_eq!(focused.name, Some("mail".into()));
+			}
+
+			event => panic!("unexpected {:?}", event),
+		}
+
+		server.join().unwrap();
+
+		// The server is gone, so the connection is closed.
+		match i3.recv().unwrap() {
+			Event::Closed(..) => (),
+			event => panic!("unexpected {:?}", event),
+		}
+
+		fs::remove_file(&path).ok();
+	}
+}
@@ -0,0 +1,208 @@
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// This file is part of dux.
+//
+// dux is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// dux is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with dux.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::thread;
+use std::cmp;
+use std::sync::Arc;
+use std::ops::Deref;
+use std::time::Duration;
+
+use channel::{self, Receiver, Sender, RecvTimeoutError};
+
+use crate::{Display, error};
+
+/// Tracks the user idle time through the MIT-SCREEN-SAVER extension.
+pub struct Idle {
+	receiver: Receiver<State>,
+	settings: Sender<Settings>,
+}
+
+/// How idle the user is, sent every time it changes.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum State {
+	/// The user is doing something.
+	Active,
+
+	/// The user has been idle for `dim` seconds.
+	Dim,
+
+	/// The user has been idle for `off` seconds.
+	Off,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Settings {
+	/// Seconds of inactivity after which the backlight is dimmed, `0` disables
+	/// it.
+	pub dim: u64,
+
+	/// Brightness the backlight is dimmed to.
+	pub level: f32,
+
+	/// Seconds of inactivity after which the backlight is turned off, `0`
+	/// disables it.
+	pub off: u64,
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Settings {
+			dim:   0,
+			level: 10.0,
+			off:   0,
+		}
+	}
+}
+
+impl Settings {
+	/// Get the state for the given idle time in milliseconds.
+	fn state(&self, idle: u64) -> State {
+		if self.off > 0 && idle >= self.off * 1_000 {
+			State::Off
+		}
+		else if self.dim > 0 && idle >= self.dim * 1_000 {
+			State::Dim
+		}
+		else {
+			State::Active
+		}
+	}
+
+	/// Get how long to wait before checking again, `None` if there's nothing to
+	/// track.
+	///
+	/// While idle it checks often so input is picked up right away, otherwise it
+	/// sleeps until the next threshold could be reached.
+	fn wait(&self, idle: u64) -> Option<u64> {
+		if self.dim == 0 && self.off == 0 {
+			return None;
+		}
+
+		if self.state(idle) != State::Active {
+			return Some(100);
+		}
+
+		[self.dim, self.off].iter()
+			.filter(|&&t| t > 0 && t * 1_000 > idle)
+			.map(|&t| t * 1_000 - idle)
+			.min()
+			.map(|t| cmp::max(t, 100))
+	}
+}
+
+impl Idle {
+	/// Spawn the thread polling the idle time.
+	pub fn spawn(display: Arc<Display>, mut current: Settings) -> error::Result<Self> {
+		if !display.capabilities().screensaver {
+			return Err(error::Error::X(error::X::MissingExtension));
+		}
+
+		let (sender, receiver)  = channel::unbounded();
+		let (settings, updater) = channel::unbounded();
+
+		thread::spawn(move || {
+			let mut state = State::Active;
+
+			loop {
+				let idle = xcb::screensaver::query_info(&display, display.root()).get_reply()
+					.map(|r| u64::from(r.ms_since_user_input()))
+					.unwrap_or(0);
+
+				if current.state(idle) != state {
+					state = current.state(idle);
+
+					if sender.send(state).is_err() {
+						break;
+					}
+				}
+
+				// A settings change wakes it up right away, when disabled it just waits
+				// for one.
+				let update = match current.wait(idle) {
+					Some(wait) => match updater.recv_timeout(Duration::from_millis(wait)) {
+						Ok(value) =>
+							Some(value),
+
+						Err(RecvTimeoutError::Timeout) =>
+							None,
+
+						Err(RecvTimeoutError::Disconnected) =>
+							break,
+					},
+
+					None => match updater.recv() {
+						Ok(value) =>
+							Some(value),
+
+						Err(_) =>
+							break,
+					},
+				};
+
+				if let Some(value) = update {
+					current = value;
+				}
+			}
+		});
+
+		Ok(Idle { receiver, settings })
+	}
+
+	/// A tracker that never sends anything, for when the extension is missing.
+	pub fn none() -> Self {
+		Idle {
+			receiver: channel::never(),
+			settings: channel::unbounded().0,
+		}
+	}
+
+	/// Change the settings, ignored when not tracking.
+	pub fn update(&self, value: Settings) {
+		self.settings.send(value).ok();
+	}
+}
+
+impl Deref for Idle {
+	type Target = Receiver<State>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.receiver
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn wait() {
+		let settings = Settings { dim: 60, off: 300, .. Settings::default() };
+
+		// Until the next threshold.
+		assert_eq!(settings.wait(0), Some(60_000));
+		assert_eq!(settings.wait(59_950), Some(100));
+
+		// Often while idle.
+		assert_eq!(settings.wait(60_000), Some(100));
+		assert_eq!(settings.state(60_000), State::Dim);
+		assert_eq!(settings.state(300_000), State::Off);
+
+		// Only the enabled thresholds count.
+		assert_eq!(Settings { dim: 0, .. settings }.wait(1_000), Some(299_000));
+		assert_eq!(Settings::default().wait(1_000), None);
+	}
+}
@@ -0,0 +1,229 @@
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// This file is part of dux.
+//
+// dux is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// dux is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with dux.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::error;
+
+/// An image file decoded to packed 8-bit RGB.
+pub struct Image {
+	pub width:  u32,
+	pub height: u32,
+	pub data:   Vec<u8>,
+}
+
+impl Image {
+	/// Open a PPM or PNG file, the format is detected from the contents.
+	pub fn open<T: AsRef<Path>>(path: T) -> error::Result<Self> {
+		let mut content = Vec::new();
+		File::open(path)?.read_to_end(&mut content)?;
+
+		if content.starts_with(b"\x89PNG") {
+			Image::png(&content)
+		}
+		else if content.starts_with(b"P6") || content.starts_with(b"P5") {
+			Image::ppm(&content)
+		}
+		else {
+			Err(error::Error::Message("unsupported image format, only PPM and PNG are".into()))
+		}
+	}
+
+	/// Decode a binary PPM or PGM image.
+	fn ppm(content: &[u8]) -> error::Result<Self> {
+		let gray       = content[1] == b'5';
+		let mut offset = 2;
+
+		// The header is made of whitespace separated numbers, any of them can be
+		// followed by a comment running to the end of the line.
+		let mut field = || -> error::Result<u32> {
+			loop {
+				match content.get(offset) {
+					Some(b'#') =>
+						while content.get(offset).map(|&c| c != b'\n').unwrap_or(false) {
+							offset += 1;
+						},
+
+					Some(c) if c.is_ascii_whitespace() =>
+						offset += 1,
+
+					_ =>
+						break,
+				}
+			}
+
+			let start = offset;
+			while content.get(offset).map(u8::is_ascii_digit).unwrap_or(false) {
+				offset += 1;
+			}
+
+			String::from_utf8_lossy(&content[start .. offset]).parse()
+				.map_err(|_| error::Error::Message("invalid PPM header".into()))
+		};
+
+		let width   = field()?;
+		let height  = field()?;
+		let maximum = field()?;
+
+		if maximum == 0 || maximum > 65535 {
+			return Err(error::Error::Message("invalid PPM maximum value".into()));
+		}
+
+		// A single whitespace separates the header from the samples.
+		let samples  = content.get(offset + 1 ..)
+			.ok_or_else(|| error::Error::Message("truncated PPM header".into()))?;
+		let channels = if gray { 1 } else { 3 };
+		let repeat   = if gray { 3 } else { 1 };
+		let size     = if maximum > 255 { 2 } else { 1 };
+
+		// The sizes come straight from the file, so they could overflow.
+		let large  = || error::Error::Message("PPM image too large".into());
+		let pixels = (width as usize).checked_mul(height as usize)
+			.filter(|pixels| pixels.checked_mul(3).is_some()).ok_or_else(large)?;
+		let count  = pixels.checked_mul(channels).ok_or_else(large)?;
+		let length = count.checked_mul(size).ok_or_else(large)?;
+
+		if samples.len() < length {
+			return Err(error::Error::Message("truncated PPM image".into()));
+		}
+
+		let mut data = Vec::with_capacity(pixels * 3);
+
+		for sample in samples.chunks_exact(size).take(count) {
+			let value = if size == 2 { u32::from(sample[0]) << 8 | u32::from(sample[1]) } else { u32::from(sample[0]) };
+			let value = (value * 255 / maximum) as u8;
+
+			for _ in 0 .. repeat {
+				data.push(value);
+			}
+		}
+
+		Ok(Image { width, height, data })
+	}
+
+	/// Decode a PNG image of any color type.
+	fn png(content: &[u8]) -> error::Result<Self> {
+		let mut decoder = png::Decoder::new(content);
+		decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+		let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+		let mut buffer = vec![0; reader.output_buffer_size()];
+		let info       = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+
+		let pixels = &buffer[.. info.buffer_size()];
+		let data   = match info.color_type {
+			png::ColorType::Rgb =>
+				pixels.to_vec(),
+
+			png::ColorType::Rgba =>
+				pixels.chunks_exact(4).flat_map(|p| p[.. 3].to_vec()).collect(),
+
+			png::ColorType::Grayscale =>
+				pixels.iter().flat_map(|&v| vec![v; 3]).collect(),
+
+			png::ColorType::GrayscaleAlpha =>
+				pixels.chunks_exact(2).flat_map(|p| vec![p[0]; 3]).collect(),
+
+			png::ColorType::Indexed =>
+				return Err(error::Error::Message("unexpected indexed PNG".into())),
+		};
+
+		Ok(Image { width: info.width, height: info.height, data })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn message(result: error::Result<Image>) -> String {
+		match result {
+			Err(error::Error::Message(message)) => message,
+			Err(err) => panic!("unexpected error: {}", err),
+			Ok(_) => panic!("unexpected success"),
+		}
+	}
+
+	#[test]
+	fn ppm() {
+		let image = Image::ppm(b"P6 2 1 255\n\xff\x00\x00\x00\x80\xff").unwrap();
+		assert_eq!((image.width, image.height), (2, 1));
+		assert_eq!(image.data, vec![0xff, 0x00, 0x00, 0x00, 0x80, 0xff]);
+
+		// Gray samples are spread over the three channels.
+		let image = Image::ppm(b"P5 2 1 255\n\x10\x20").unwrap();
+		assert_eq!(image.data, vec![0x10, 0x10, 0x10, 0x20, 0x20, 0x20]);
+	}
+
+	#[test]
+	fn ppm_comments() {
+		let image = Image::ppm(b"P5\n# made by hand\n1 # width\n1\n# maximum\n255\n\x42").unwrap();
+		assert_eq!((image.width, image.height), (1, 1));
+		assert_eq!(image.data, vec![0x42; 3]);
+	}
+
+	#[test]
+	fn ppm_wide() {
+		// Samples are big endian and scaled down to 8 bits.
+		let image = Image::ppm(b"P5 2 1 65535\n\xff\xff\x80\x00").unwrap();
+		assert_eq!(image.data, vec![0xff, 0xff, 0xff, 0x7f, 0x7f, 0x7f]);
+
+		let image = Image::ppm(b"P6 1 1 1023\n\x03\xff\x00\x00\x02\x00").unwrap();
+		assert_eq!(image.data, vec![0xff, 0x00, 0x7f]);
+	}
+
+	#[test]
+	fn ppm_invalid() {
+		assert_eq!(message(Image::ppm(b"P6 1 1 255")), "truncated PPM header");
+		assert_eq!(message(Image::ppm(b"P6 1 1 255\n\xff")), "truncated PPM image");
+		assert_eq!(message(Image::ppm(b"P6 1 1")), "invalid PPM header");
+		assert_eq!(message(Image::ppm(b"P6 1 1 0\n\xff")), "invalid PPM maximum value");
+		assert_eq!(message(Image::ppm(b"P6 4294967295 4294967295 65535\n")), "PPM image too large");
+		assert_eq!(message(Image::ppm(b"P6 65536 65536 255\n")), "truncated PPM image");
+	}
+
+	fn encode(width: u32, height: u32, color: png::ColorType, pixels: &[u8]) -> Vec<u8> {
+		let mut content = Vec::new();
+
+		{
+			let mut encoder = png::Encoder::new(&mut content, width, height);
+			encoder.set_color(color);
+			encoder.set_depth(png::BitDepth::Eight);
+			encoder.write_header().unwrap().write_image_data(pixels).unwrap();
+		}
+
+		content
+	}
+
+	#[test]
+	fn png() {
+		let image = Image::png(&encode(2, 1, png::ColorType::Rgb, &[1, 2, 3, 4, 5, 6])).unwrap();
+		assert_eq!((image.width, image.height), (2, 1));
+		assert_eq!(image.data, vec![1, 2, 3, 4, 5, 6]);
+
+		// The alpha channel is dropped.
+		let image = Image::png(&encode(1, 1, png::ColorType::Rgba, &[1, 2, 3, 4])).unwrap();
+		assert_eq!(image.data, vec![1, 2, 3]);
+
+		let image = Image::png(&encode(2, 1, png::ColorType::Grayscale, &[7, 9])).unwrap();
+		assert_eq!(image.data, vec![7, 7, 7, 9, 9, 9]);
+
+		assert!(Image::png(b"\x89PNG\r\n").is_err());
+	}
+}
@@ -15,17 +15,144 @@
 // You should have received a copy of the GNU General Public License
 // along with dux.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::env;
+use std::fs;
+use std::mem;
 use std::thread;
 use std::ops::Deref;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
 
 use dbus;
-use channel::{self, Receiver};
+use dbus::arg::{Variant, RefArg};
+use dbus::stdintf::org_freedesktop_dbus::Properties;
+use json::{object, JsonValue};
+use log::warn;
+use channel::{self, Receiver, Sender};
 
-use crate::{error, backlight};
+use crate::{error, backlight, socket};
 
-/// DBus interface handler.
+/// DBus and control socket interface handler.
 pub struct Interface {
 	receiver: Receiver<Event>,
+	status:   Arc<Mutex<Status>>,
+	updates:  Vec<Sender<(Status, Status)>>,
+	waker:    Option<UnixStream>,
+	socket:   Option<PathBuf>,
+}
+
+/// The state of the daemon exposed through the properties.
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct Status {
+	/// The brightness of the first backlight.
+	pub brightness: f32,
+
+	/// The brightness the first backlight is adapting to, which differs from
+	/// the actual one while it's held.
+	pub target: f32,
+
+	/// The luminance of the output of the first backlight.
+	pub luminance: f32,
+
+	/// Why the backlight is at its current value.
+	pub reason: String,
+
+	pub mode:     Mode,
+	pub profile:  String,
+	pub backend:  String,
+	pub profiles: Vec<String>,
+}
+
+/// The exposed properties.
+pub const PROPERTIES: &[&str] = &["Mode", "Profile", "Brightness", "Target", "Luminance", "Backend", "Reason"];
+
+impl Status {
+	/// Get the names of the properties that changed from the given status.
+	pub fn changed(&self, old: &Status) -> Vec<&'static str> {
+		PROPERTIES.iter().cloned().filter(|&name| self.json(&[name]) != old.json(&[name])).collect()
+	}
+
+	/// Get the given properties as JSON.
+	pub fn json(&self, names: &[&str]) -> JsonValue {
+		let mut result = object!{};
+
+		for &name in names {
+			result[name] = match name {
+				"Mode"       => self.mode.name().into(),
+				"Profile"    => self.profile.clone().into(),
+				"Brightness" => self.brightness.into(),
+				"Target"     => self.target.into(),
+				"Luminance"  => self.luminance.into(),
+				"Backend"    => self.backend.clone().into(),
+				"Reason"     => self.reason.clone().into(),
+				_            => continue,
+			};
+		}
+
+		result
+	}
+
+	/// Get the given properties as DBus variants.
+	fn variants(&self, names: &[&str]) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+		self.json(names).entries().map(|(name, value)| {
+			let value = if let Some(value) = value.as_str() {
+				Box::new(value.to_owned()) as Box<dyn RefArg>
+			}
+			else {
+				Box::new(value.as_f64().unwrap_or(0.0)) as Box<dyn RefArg>
+			};
+
+			(name.to_owned(), Variant(value))
+		}).collect()
+	}
+
+	/// Apply the given JSON properties, returns the names of the known ones.
+	pub fn apply(&mut self, properties: &JsonValue) -> Vec<&'static str> {
+		let mut names = Vec::new();
+
+		for &name in PROPERTIES {
+			let value = &properties[name];
+
+			if value.is_null() {
+				continue;
+			}
+
+			match name {
+				"Mode"       => self.mode = value.as_str().and_then(Mode::parse).unwrap_or_default(),
+				"Profile"    => self.profile = value.as_str().unwrap_or("").into(),
+				"Brightness" => self.brightness = value.as_f32().unwrap_or(0.0),
+				"Target"     => self.target = value.as_f32().unwrap_or(0.0),
+				"Luminance"  => self.luminance = value.as_f32().unwrap_or(0.0),
+				"Backend"    => self.backend = value.as_str().unwrap_or("").into(),
+				"Reason"     => self.reason = value.as_str().unwrap_or("").into(),
+				_            => continue,
+			}
+
+			names.push(name);
+		}
+
+		names
+	}
+
+	/// Convert DBus properties to JSON.
+	fn properties(variants: &HashMap<String, Variant<Box<dyn RefArg>>>) -> JsonValue {
+		let mut result = object!{};
+
+		for (name, value) in variants {
+			if let Some(value) = value.0.as_str() {
+				result[name.as_str()] = value.into();
+			}
+			else if let Some(value) = value.0.as_f64() {
+				result[name.as_str()] = value.into();
+			}
+		}
+
+		result
+	}
 }
 
 #[derive(Debug)]
@@ -34,9 +161,15 @@ pub enum Event {
 	Profile(String),
 	Brightness(f32),
 	Save,
+	Reload,
 	Stop,
 
 	ScreenSaver(bool),
+	Locked(bool),
+
+	/// The system is going to sleep, or woke up; going to sleep is delayed
+	/// until the lock is dropped.
+	Sleep(bool, Option<dbus::OwnedFd>),
 }
 
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
@@ -65,76 +198,185 @@ impl Mode {
 			_           => None,
 		}
 	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Mode::Manual    => "manual",
+			Mode::Desktop   => "desktop",
+			Mode::Window    => "window",
+			Mode::Luminance => "luminance",
+			Mode::Time      => "time",
+		}
+	}
 }
 
 impl Interface {
 	/// Change the adaptive mode.
 	pub fn mode<T: Into<String>>(value: T) -> error::Result<()> {
-		dbus::Connection::get_private(dbus::BusType::Session)?
-			.send(dbus::Message::new_method_call(
-				"meh.rust.Backlight",
-				"/meh/rust/Backlight",
-				"meh.rust.Backlight",
-				"Mode")?
-					.append1(value.into()))?;
-
-		Ok(())
+		Interface::call("Mode", Some(value.into().into()))
 	}
 
 	/// Change the current profile.
 	pub fn profile<T: Into<String>>(value: T) -> error::Result<()> {
-		dbus::Connection::get_private(dbus::BusType::Session)?
-			.send(dbus::Message::new_method_call(
-				"meh.rust.Backlight",
-				"/meh/rust/Backlight",
-				"meh.rust.Backlight",
-				"Profile")?
-					.append1(value.into()))?;
-
-		Ok(())
+		Interface::call("Profile", Some(value.into().into()))
 	}
 
 	/// Send a brightness change.
 	pub fn brightness(value: f32) -> error::Result<()> {
-		dbus::Connection::get_private(dbus::BusType::Session)?
-			.send(dbus::Message::new_method_call(
-				"meh.rust.Backlight",
-				"/meh/rust/Backlight",
-				"meh.rust.Backlight",
-				"Brightness")?
-					.append1(f64::from(backlight::clamp(value))))?;
-
-		Ok(())
+		Interface::call("Brightness", Some(backlight::clamp(value).into()))
 	}
 
 	/// Send a save event.
 	pub fn save() -> error::Result<()> {
-		dbus::Connection::get_private(dbus::BusType::Session)?
-			.send(dbus::Message::new_method_call(
-				"meh.rust.Backlight",
-				"/meh/rust/Backlight",
-				"meh.rust.Backlight",
-				"Save")?)?;
+		Interface::call("Save", None)
+	}
 
-		Ok(())
+	/// Send a reload event.
+	pub fn reload() -> error::Result<()> {
+		Interface::call("Reload", None)
 	}
 
 	/// Send a stop event.
 	pub fn stop() -> error::Result<()> {
-		dbus::Connection::get_private(dbus::BusType::Session)?
-			.send(dbus::Message::new_method_call(
-				"meh.rust.Backlight",
-				"/meh/rust/Backlight",
-				"meh.rust.Backlight",
-				"Stop")?)?;
+		Interface::call("Stop", None)
+	}
+
+	/// Connect to the session bus, if there's none the socket is used instead.
+	fn bus() -> Option<dbus::Connection> {
+		dbus::Connection::get_private(dbus::BusType::Session).ok()
+	}
+
+	/// Whether the error means the daemon isn't on the bus, in which case it
+	/// could still be on the socket.
+	fn missing(err: &error::Error) -> bool {
+		if let error::Error::DBus(error::DBus::Internal(ref err)) = *err {
+			matches!(err.name(), Some("org.freedesktop.DBus.Error.ServiceUnknown") | Some("org.freedesktop.DBus.Error.NameHasNoOwner"))
+		}
+		else {
+			false
+		}
+	}
+
+	/// Call a method of the running daemon, with a string or number argument.
+	fn call(method: &str, value: Option<JsonValue>) -> error::Result<()> {
+		if let Some(c) = Interface::bus() {
+			match Interface::request(&c, method, value.clone()) {
+				Err(ref err) if Interface::missing(err) => (),
+				result => return result,
+			}
+		}
+
+		socket::Client::open(socket::path()?)?.request(method, value)?;
+
+		Ok(())
+	}
+
+	/// Call a method over the bus, waiting for the daemon to get it.
+	fn request(c: &dbus::Connection, method: &str, value: Option<JsonValue>) -> error::Result<()> {
+		let message = dbus::Message::new_method_call(
+			"meh.rust.Backlight",
+			"/meh/rust/Backlight",
+			"meh.rust.Backlight",
+			method)?;
+
+		c.send_with_reply_and_block(match value {
+			Some(JsonValue::Number(value)) =>
+				message.append1(f64::from(value)),
+
+			Some(value) =>
+				message.append1(value.as_str().unwrap_or("").to_owned()),
+
+			None =>
+				message,
+		}, 5_000)?;
 
 		Ok(())
 	}
 
+	/// Get the state of the running daemon.
+	pub fn status() -> error::Result<Status> {
+		if let Some(c) = Interface::bus() {
+			match Interface::query(&c) {
+				Err(ref err) if Interface::missing(err) => (),
+				result => return result,
+			}
+		}
+
+		socket::Client::open(socket::path()?)?.status()
+	}
+
+	/// Get all the properties and the profiles over the bus.
+	fn query(c: &dbus::Connection) -> error::Result<Status> {
+		let mut status = Status::default();
+		status.apply(&Status::properties(&c.with_path("meh.rust.Backlight", "/meh/rust/Backlight", 1_000).get_all("meh.rust.Backlight")?));
+
+		let reply = c.send_with_reply_and_block(dbus::Message::new_method_call(
+			"meh.rust.Backlight",
+			"/meh/rust/Backlight",
+			"meh.rust.Backlight",
+			"ListProfiles")?, 1_000)?;
+
+		status.profiles = reply.get1().unwrap_or_default();
+
+		Ok(status)
+	}
+
+	/// Follow the state of the running daemon, the callback is called with the
+	/// current state first and then with the names of the changed properties
+	/// every time any changes.
+	pub fn watch<F: FnMut(&Status, &[&str])>(mut callback: F) -> error::Result<()> {
+		// The changes are listened to first, so none is missed.
+		if let Some(c) = Interface::bus() {
+			c.add_match("type='signal',sender='meh.rust.Backlight',path='/meh/rust/Backlight',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'")?;
+
+			match Interface::query(&c) {
+				Ok(mut status) => {
+					callback(&status, PROPERTIES);
+
+					for item in c.iter(1_000_000) {
+						if let dbus::ConnectionItem::Signal(m) = item {
+							if let (Some("meh.rust.Backlight"), Some(changed)) = m.get2::<&str, HashMap<String, Variant<Box<dyn RefArg>>>>() {
+								let names = status.apply(&Status::properties(&changed));
+
+								if !names.is_empty() {
+									callback(&status, &names);
+								}
+							}
+						}
+					}
+
+					return Ok(());
+				}
+
+				Err(ref err) if Interface::missing(err) =>
+					(),
+
+				Err(err) =>
+					return Err(err),
+			}
+		}
+
+		let mut events = socket::Client::open(socket::path()?)?;
+		events.request("Subscribe", None)?;
+
+		let mut status = socket::Client::open(socket::path()?)?.status()?;
+		callback(&status, PROPERTIES);
+
+		loop {
+			let names = status.apply(&events.receive()?["changed"]);
+
+			if !names.is_empty() {
+				callback(&status, &names);
+			}
+		}
+	}
+
 	/// Spawn the server.
 	pub fn spawn() -> error::Result<Self> {
 		let (sender, receiver)     = channel::bounded(1);
 		let (g_sender, g_receiver) = channel::unbounded::<error::Result<()>>();
+		let (bus, changes)         = channel::unbounded::<(Status, Status)>();
+		let status                 = Arc::new(Mutex::new(Status::default()));
 
 		macro_rules! dbus {
 			(connect system) => (
@@ -173,7 +415,9 @@ impl Interface {
 			);
 
 			(watch $conn:expr, $filter:expr) => (
-				$conn.add_match($filter)
+				if let Err(err) = $conn.add_match($filter) {
+					warn!("could not watch `{}`: {}", $filter, err);
+				}
 			);
 
 			(ready) => (
@@ -205,12 +449,24 @@ impl Interface {
 			});
 		}
 
+		let system  = sender.clone();
+		let control = sender.clone();
+		let shared  = status.clone();
+
+		// The bus thread sleeps until there's something to do, so it's woken up
+		// through the socket when there are changes to send.
+		let (waker, woken) = UnixStream::pair()?;
+		waker.set_nonblocking(true)?;
+		woken.set_nonblocking(true)?;
+
 		thread::spawn(move || {
+			let status = shared;
 			let c = dbus!(connect session);
 			let f = dbus::tree::Factory::new_sync::<()>();
 
 			dbus!(register c, "meh.rust.Backlight");
 			dbus!(watch c, "interface='org.gnome.ScreenSaver',member='ActiveChanged'");
+			dbus!(watch c, "interface='org.freedesktop.ScreenSaver',member='ActiveChanged'");
 			dbus!(ready);
 
 			let tree = f.tree(())
@@ -254,31 +510,295 @@ impl Interface {
 						Ok(vec![m.msg.method_return()])
 					})))
 
+					.add_m(f.method("Reload", (), cloning!([sender] move |m| {
+						sender.send(Event::Reload).unwrap();
+
+						Ok(vec![m.msg.method_return()])
+					})))
+
 					.add_m(f.method("Stop", (), cloning!([sender] move |m| {
 						sender.send(Event::Stop).unwrap();
 
 						Ok(vec![m.msg.method_return()])
+					})))
+
+					.add_m(f.method("GetTarget", (), cloning!([status] move |m| {
+						Ok(vec![m.msg.method_return().append1(f64::from(status.lock().unwrap().target))])
+					})).outarg::<f64, _>("value"))
+
+					.add_m(f.method("ListProfiles", (), cloning!([status] move |m| {
+						Ok(vec![m.msg.method_return().append1(status.lock().unwrap().profiles.clone())])
+					})).outarg::<Vec<String>, _>("profiles"))
+
+					.add_p(f.property::<f64, _>("Brightness", ()).on_get(cloning!([status] move |i, _| {
+						i.append(f64::from(status.lock().unwrap().brightness));
+						Ok(())
+					})))
+
+					.add_p(f.property::<f64, _>("Target", ()).on_get(cloning!([status] move |i, _| {
+						i.append(f64::from(status.lock().unwrap().target));
+						Ok(())
+					})))
+
+					.add_p(f.property::<f64, _>("Luminance", ()).on_get(cloning!([status] move |i, _| {
+						i.append(f64::from(status.lock().unwrap().luminance));
+						Ok(())
+					})))
+
+					.add_p(f.property::<String, _>("Reason", ()).on_get(cloning!([status] move |i, _| {
+						i.append(status.lock().unwrap().reason.clone());
+						Ok(())
+					})))
+
+					.add_p(f.property::<String, _>("Mode", ()).on_get(cloning!([status] move |i, _| {
+						i.append(status.lock().unwrap().mode.name());
+						Ok(())
+					})))
+
+					.add_p(f.property::<String, _>("Profile", ()).on_get(cloning!([status] move |i, _| {
+						i.append(status.lock().unwrap().profile.clone());
+						Ok(())
+					})))
+
+					.add_p(f.property::<String, _>("Backend", ()).on_get(cloning!([status] move |i, _| {
+						i.append(status.lock().unwrap().backend.clone());
+						Ok(())
 					})))));
 
 			tree.set_registered(&c, true).unwrap();
-			for item in tree.run(&c, c.iter(1_000_000)) {
-				if let dbus::ConnectionItem::Signal(m) = item {
-					match (&*m.interface().unwrap(), &*m.member().unwrap()) {
-						("org.gnome.ScreenSaver", "ActiveChanged") => {
-							if let Some(status) = m.get1() {
-								sender.send(Event::ScreenSaver(status)).unwrap();
+
+			// Sleep until either the bus or an update wakes the thread up.
+			loop {
+				for item in tree.run(&c, dbus::ConnectionItems::new(&c, None, true)) {
+					if let dbus::ConnectionItem::Signal(m) = item {
+						match (&*m.interface().unwrap(), &*m.member().unwrap()) {
+							("org.gnome.ScreenSaver", "ActiveChanged") | ("org.freedesktop.ScreenSaver", "ActiveChanged") => {
+								if let Some(status) = m.get1() {
+									sender.send(Event::ScreenSaver(status)).unwrap();
+								}
 							}
+
+							_ => ()
 						}
+					}
+				}
 
-						_ => ()
+				let mut fds = c.watch_fds().iter().map(dbus::Watch::to_pollfd).collect::<Vec<_>>();
+				fds.push(libc::pollfd { fd: woken.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+
+				if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+					continue;
+				}
+
+				let (wake, bus) = fds.split_last().unwrap();
+
+				for fd in bus.iter().filter(|fd| fd.revents != 0) {
+					c.watch_handle(fd.fd, dbus::WatchEvent::from_revents(fd.revents));
+				}
+
+				if wake.revents != 0 {
+					while (&woken).read(&mut [0; 64]).map(|n| n > 0).unwrap_or(false) { }
+
+					while let Ok((old, new)) = changes.try_recv() {
+						let changed = new.changed(&old);
+
+						if !changed.is_empty() {
+							c.send(dbus::Message::new_signal("/meh/rust/Backlight", "org.freedesktop.DBus.Properties", "PropertiesChanged").unwrap()
+								.append3("meh.rust.Backlight", new.variants(&changed), Vec::<String>::new())).ok();
+						}
 					}
 				}
 			}
 		});
 
-		dbus!(check)?;
+		// Without a session bus only the socket is there, but a daemon already on
+		// the bus means there's one running.
+		let bus = match dbus!(check) {
+			Ok(()) =>
+				Some(bus),
 
-		Ok(Interface { receiver })
+			Err(err @ error::Error::DBus(error::DBus::AlreadyRegistered)) =>
+				return Err(err),
+
+			Err(err) => {
+				warn!("session bus not available: {}", err);
+				None
+			}
+		};
+
+		let socket = match socket::path().and_then(|path| socket::serve(&path, control, status.clone()).map(|updates| (path, updates))) {
+			Ok(socket) =>
+				Some(socket),
+
+			Err(err) if bus.is_some() => {
+				warn!("control socket not available: {}", err);
+				None
+			}
+
+			Err(err) =>
+				return Err(err),
+		};
+
+		let waker   = bus.as_ref().map(|_| waker);
+		let updates = bus.into_iter().chain(socket.as_ref().map(|(_, updates)| updates.clone())).collect();
+		let socket  = socket.map(|(path, _)| path);
+
+		// The session lock state and sleep come from logind, which might not be
+		// there.
+		thread::spawn(move || {
+			let result = dbus::Connection::get_private(dbus::BusType::System)
+				.map_err(error::Error::from)
+				.and_then(|c| Interface::logind(&c, &system));
+
+			if let Err(err) = result {
+				warn!("logind not available: {}", err);
+			}
+		});
+
+		Ok(Interface { receiver, status, updates, waker, socket })
+	}
+
+	/// Stop listening on the socket.
+	pub fn close(&self) {
+		if let Some(path) = &self.socket {
+			fs::remove_file(path).ok();
+		}
+	}
+
+	/// Update the exposed state, the changed properties are signaled.
+	pub fn update(&self, value: Status) {
+		let mut status = self.status.lock().unwrap();
+
+		if *status != value {
+			let old = mem::replace(&mut *status, value.clone());
+
+			for updates in &self.updates {
+				updates.send((old.clone(), value.clone())).ok();
+			}
+
+			// It's already awake if the socket is full.
+			if let Some(mut waker) = self.waker.as_ref() {
+				waker.write_all(&[0]).ok();
+			}
+		}
+	}
+
+	/// Get the logind session, the one in `XDG_SESSION_ID` or the one logind
+	/// picks for the user.
+	fn session(c: &dbus::Connection) -> error::Result<dbus::Path<'static>> {
+		let id    = env::var("XDG_SESSION_ID").unwrap_or_else(|_| "auto".into());
+		let reply = c.send_with_reply_and_block(dbus::Message::new_method_call(
+			"org.freedesktop.login1",
+			"/org/freedesktop/login1",
+			"org.freedesktop.login1.Manager",
+			"GetSession")?
+				.append1(id), 1_000)?;
+
+		Ok(reply.get1::<dbus::Path>()
+			.ok_or_else(|| "invalid logind session".to_owned())?
+			.into_static())
+	}
+
+	/// Delay sleep until the returned lock is dropped.
+	fn inhibit(c: &dbus::Connection) -> error::Result<dbus::OwnedFd> {
+		let reply = c.send_with_reply_and_block(dbus::Message::new_method_call(
+			"org.freedesktop.login1",
+			"/org/freedesktop/login1",
+			"org.freedesktop.login1.Manager",
+			"Inhibit")?
+				.append2("sleep", "dux")
+				.append2("Saving the brightness", "delay"), 1_000)?;
+
+		Ok(reply.get1::<dbus::OwnedFd>()
+			.ok_or_else(|| "invalid logind inhibitor".to_owned())?)
+	}
+
+	/// Follow sleep and the lock state of the logind session on the given bus.
+	fn logind(c: &dbus::Connection, sender: &Sender<Event>) -> error::Result<()> {
+		c.add_match("type='signal',sender='org.freedesktop.login1',path='/org/freedesktop/login1',interface='org.freedesktop.login1.Manager',member='PrepareForSleep'")?;
+
+		// The lock goes along with the sleep event, so the cache is saved and the
+		// brightness captured before the system actually sleeps.
+		macro_rules! inhibit {
+			() => (
+				match Interface::inhibit(c) {
+					Ok(fd) =>
+						Some(fd),
+
+					Err(err) => {
+						warn!("could not delay sleep: {}", err);
+						None
+					}
+				}
+			);
+		}
+
+		let mut inhibitor = inhibit!();
+
+		// Sleep is followed even without a session.
+		let session = match Interface::session(c) {
+			Ok(path) => {
+				c.add_match(&format!("type='signal',sender='org.freedesktop.login1',path='{}',interface='org.freedesktop.login1.Session'", path))?;
+				c.add_match(&format!("type='signal',sender='org.freedesktop.login1',path='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'", path))?;
+
+				// The session could be locked already.
+				if c.with_path("org.freedesktop.login1", path.clone(), 1_000).get::<bool>("org.freedesktop.login1.Session", "LockedHint")? {
+					sender.send(Event::Locked(true)).unwrap();
+				}
+
+				Some(path)
+			}
+
+			Err(err) => {
+				warn!("no logind session, the lock state is not followed: {}", err);
+				None
+			}
+		};
+
+		for item in c.iter(1_000_000) {
+			if let dbus::ConnectionItem::Signal(m) = item {
+				let current = session.is_some() && m.path() == session;
+
+				match (&*m.interface().unwrap(), &*m.member().unwrap()) {
+					("org.freedesktop.login1.Manager", "PrepareForSleep") => {
+						match m.get1() {
+							Some(true) =>
+								sender.send(Event::Sleep(true, inhibitor.take())).unwrap(),
+
+							Some(false) => {
+								if inhibitor.is_none() {
+									inhibitor = inhibit!();
+								}
+
+								sender.send(Event::Sleep(false, None)).unwrap();
+							}
+
+							None => ()
+						}
+					}
+
+					("org.freedesktop.login1.Session", "Lock") if current => {
+						sender.send(Event::Locked(true)).unwrap();
+					}
+
+					("org.freedesktop.login1.Session", "Unlock") if current => {
+						sender.send(Event::Locked(false)).unwrap();
+					}
+
+					("org.freedesktop.DBus.Properties", "PropertiesChanged") if current => {
+						if let (Some("org.freedesktop.login1.Session"), Some(changed)) = m.get2::<&str, HashMap<String, Variant<Box<dyn RefArg>>>>() {
+							if let Some(&value) = changed.get("LockedHint").and_then(|v| (*v.0).as_any().downcast_ref::<bool>()) {
+								sender.send(Event::Locked(value)).unwrap();
+							}
+						}
+					}
+
+					_ => ()
+				}
+			}
+		}
+
+		Ok(())
 	}
 }
 
@@ -289,3 +809,181 @@ impl Deref for Interface {
 		&self.receiver
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs;
+	use std::process::{self, Command, Stdio};
+	use std::io::{BufRead, BufReader, Read};
+	use std::os::unix::io::IntoRawFd;
+	use std::os::unix::net::UnixStream;
+	use std::time::Duration;
+
+	#[test]
+	fn properties() {
+		let old = Status {
+			brightness: 40.0,
+			target:     40.0,
+			luminance:  0.5,
+			reason:     "fade: luminance 0.5".into(),
+			mode:       Mode::Luminance,
+			profile:    "default".into(),
+			backend:    "randr".into(),
+			profiles:   vec!["default".into()],
+		};
+
+		assert!(old.changed(&old).is_empty());
+
+		// The profiles aren't a property.
+		let new     = Status { brightness: 60.0, target: 80.0, profile: "night".into(), profiles: Vec::new(), .. old.clone() };
+		let changed = new.changed(&old);
+		assert_eq!(changed, ["Profile", "Brightness", "Target"]);
+
+		// Applying the changes gets to the same state.
+		let mut status = old.clone();
+		assert_eq!(status.apply(&new.json(&changed)), changed);
+		assert_eq!(status, Status { profiles: old.profiles.clone(), .. new.clone() });
+
+		// Same over DBus.
+		assert_eq!(Status::properties(&new.variants(&changed)), new.json(&changed));
+	}
+
+	#[test]
+	fn logind() {
+		let path = env::temp_dir().join(format!("dux-bus-{}", process::id()));
+		fs::remove_file(&path).ok();
+
+		// A private bus, the test is skipped if there's no bus daemon around.
+		let mut daemon = match Command::new("dbus-daemon")
+			.args(["--session", "--nofork", "--print-address"])
+			.arg(format!("--address=unix:path={}", path.display()))
+			.stdout(Stdio::piped())
+			.stderr(Stdio::null())
+			.spawn()
+		{
+			Ok(child) =>
+				child,
+
+			Err(_) => {
+				eprintln!("dbus-daemon not found, skipping");
+				return;
+			}
+		};
+
+		let mut address = String::new();
+		BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+		let address = address.trim().to_owned();
+
+		// A stand-in logind with an unlocked session.
+		let logind = dbus::Connection::open_private(&address).unwrap();
+		logind.register().unwrap();
+		logind.register_name("org.freedesktop.login1", 0).unwrap();
+
+		// Calls are answered by hand, instead of libdbus replying they're unknown.
+		let mut queue = logind.replace_message_callback(None).unwrap();
+		logind.replace_message_callback(Some(Box::new(move |c, m| {
+			queue(c, m);
+			true
+		})));
+
+		let (sender, receiver) = channel::unbounded();
+		{
+			let address = address.clone();
+
+			thread::spawn(move || {
+				let c = dbus::Connection::open_private(&address).unwrap();
+				c.register().unwrap();
+
+				Interface::logind(&c, &sender).unwrap();
+			});
+		}
+
+		// Answer the given call from the daemon.
+		let answer = |name: &str, reply: &dyn Fn(&dbus::Message) -> dbus::Message| {
+			loop {
+				for m in logind.incoming(1_000) {
+					if m.member().map(|m| &*m == name).unwrap_or(false) {
+						logind.send(reply(&m)).unwrap();
+						return m;
+					}
+				}
+			}
+		};
+
+		// The lock is handed out as one end of a socket, so it can be told when
+		// the other one is closed.
+		let inhibit = || {
+			let (lock, peer) = UnixStream::pair().unwrap();
+			let m = answer("Inhibit", &move |m| dbus::Message::new_method_return(m).unwrap()
+				.append1(dbus::OwnedFd::new(lock.try_clone().unwrap().into_raw_fd())));
+
+			assert_eq!(m.get_items()[0], "sleep".into());
+			assert_eq!(m.get_items()[3], "delay".into());
+
+			peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+			peer
+		};
+
+		let released = |mut peer: &UnixStream| {
+			peer.read(&mut [0]).map(|n| n == 0).unwrap_or(false)
+		};
+
+		// The lock is taken before the session is looked up, so once the lookup
+		// comes in the signals can be sent.
+		let mut peer = inhibit();
+		let session = "/org/freedesktop/login1/session/c2";
+		answer("GetSession", &|m| dbus::Message::new_method_return(m).unwrap().append1(dbus::Path::from(session)));
+		answer("Get", &|m| dbus::Message::new_method_return(m).unwrap().append1(Variant(false)));
+
+		logind.send(dbus::Message::new_signal("/org/freedesktop/login1", "org.freedesktop.login1.Manager", "PrepareForSleep").unwrap()
+			.append1(true)).unwrap();
+
+		// Sleep waits until the event has been handled.
+		match receiver.recv_timeout(Duration::from_secs(5)) {
+			Ok(Event::Sleep(true, Some(lock))) => {
+				peer.set_nonblocking(true).unwrap();
+				assert!(!released(&peer));
+				peer.set_nonblocking(false).unwrap();
+
+				drop(lock);
+				assert!(released(&peer));
+			}
+
+			event => panic!("unexpected {:?}", event),
+		}
+
+		// The lock is taken again on wake up.
+		logind.send(dbus::Message::new_signal("/org/freedesktop/login1", "org.freedesktop.login1.Manager", "PrepareForSleep").unwrap()
+			.append1(false)).unwrap();
+		peer = inhibit();
+
+		match receiver.recv_timeout(Duration::from_secs(5)) {
+			Ok(Event::Sleep(false, None)) => (),
+			event => panic!("unexpected {:?}", event),
+		}
+
+		// The lock state follows the property of the session.
+		for &value in &[true, false] {
+			let mut changed = HashMap::new();
+			changed.insert("LockedHint", Variant(value));
+
+			logind.send(dbus::Message::new_signal(session, "org.freedesktop.DBus.Properties", "PropertiesChanged").unwrap()
+				.append3("org.freedesktop.login1.Session", changed, Vec::<String>::new())).unwrap();
+
+			match receiver.recv_timeout(Duration::from_secs(5)) {
+				Ok(Event::Locked(locked)) if locked == value => (),
+				event => panic!("unexpected {:?}", event),
+			}
+		}
+
+				// Signals from other paths or interfaces are ignored.
+		logind.send(dbus::Message::new_signal("/org/freedesktop/login1/session/c1", "org.freedesktop.login1.Session", "Lock").unwrap()).unwrap();
+		assert!(receiver.recv_timeout(Duration::from_millis(500)).is_err());
+		drop(peer);
+
+		daemon.kill().ok();
+		daemon.wait().ok();
+		fs::remove_file(&path).ok();
+	}
+}
@@ -0,0 +1,1262 @@
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// This file is part of dux.
+//
+// dux is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// dux is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with dux.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp;
+use std::time::Instant;
+
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+use crate::display;
+
+/// Computes luminances from raw pixel buffers.
+///
+/// Instead of keeping the luminance of every pixel around the buffer is split
+/// into tiles, each keeping the statistics of the pixels within it, so when a
+/// section changes the tiles it touches are recomputed and their previous
+/// contribution is swapped with the new one.
+///
+/// It knows nothing about X, so it can be fed screenshots as well as the
+/// actual screen contents.
+pub struct Engine {
+	format: Format,
+	width:  u32,
+	height: u32,
+
+	tiles:     Vec<Tile>,
+	dirty:     Vec<bool>,
+	columns:   u32,
+	stride:    u32,
+	model:     Model,
+	tables:    Tables,
+	metric:    Metric,
+	weighting: Weighting,
+	focus:     Option<xcb::Rectangle>,
+	excluded:  Vec<xcb::Rectangle>,
+	total:     Area,
+	regions:   Vec<Region>,
+}
+
+/// How pixels are laid out in the fetched images.
+#[derive(Copy, Clone, Debug)]
+pub struct Format {
+	depth: u8,
+	bytes: usize,
+	msb:   bool,
+
+	red:   Channel,
+	green: Channel,
+	blue:  Channel,
+}
+
+/// Where a color channel sits within a pixel.
+#[derive(Copy, Clone, Debug)]
+struct Channel {
+	shift: u32,
+	bits:  u32,
+}
+
+/// The lookup tables for each channel, indexed by the raw channel value.
+type Tables = [Vec<f32>; 3];
+
+impl Channel {
+	/// Find the channel from its mask, which must be contiguous and at most 16
+	/// bits wide.
+	fn new(mask: u32) -> Option<Channel> {
+		let shift = mask.trailing_zeros();
+		let bits  = mask.count_ones();
+
+		if bits == 0 || bits > 16 || (mask >> shift).count_ones() != (mask >> shift).trailing_ones() {
+			return None;
+		}
+
+		Some(Channel { shift, bits })
+	}
+
+	/// Extract the channel value from a pixel.
+	fn get(&self, pixel: u32) -> usize {
+		((pixel >> self.shift) & ((1 << self.bits) - 1)) as usize
+	}
+}
+
+impl Format {
+	/// Get the format for the given visual, if it's a supported true color one.
+	pub fn new(visual: &display::Visual) -> Option<Format> {
+		if !matches!(visual.bpp, 16 | 24 | 32) || visual.depth > visual.bpp {
+			return None;
+		}
+
+		Some(Format {
+			depth: visual.depth,
+			bytes: visual.bpp as usize / 8,
+			msb:   visual.msb,
+
+			red:   Channel::new(visual.red)?,
+			green: Channel::new(visual.green)?,
+			blue:  Channel::new(visual.blue)?,
+		})
+	}
+
+	/// Get the number of significant bits in a pixel.
+	pub fn depth(&self) -> u8 {
+		self.depth
+	}
+
+	/// Get the format for packed 8-bit RGB, as found in most image files.
+	pub fn rgb() -> Format {
+		Format::new(&display::Visual {
+			depth: 24,
+			bpp:   24,
+
+			red:   0xff0000,
+			green: 0x00ff00,
+			blue:  0x0000ff,
+
+			msb: true,
+		}).unwrap()
+	}
+
+	/// Read a single pixel.
+	fn pixel(&self, data: &[u8]) -> u32 {
+		match (self.bytes, self.msb) {
+			(2, false) => u32::from(LittleEndian::read_u16(data)),
+			(2, true)  => u32::from(BigEndian::read_u16(data)),
+			(3, false) => LittleEndian::read_u24(data),
+			(3, true)  => BigEndian::read_u24(data),
+			(_, false) => LittleEndian::read_u32(data),
+			(_, true)  => BigEndian::read_u32(data),
+		}
+	}
+}
+
+/// How the luminance of a pixel is computed.
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default)]
+pub enum Model {
+	/// Rec.601 luma on the gamma encoded values.
+	#[default]
+	Luma,
+
+	/// Rec.709 relative luminance on the linearized sRGB values.
+	Linear,
+}
+
+impl Model {
+	pub fn parse<T: AsRef<str>>(value: T) -> Option<Model> {
+		match value.as_ref() {
+			"luma"   => Some(Model::Luma),
+			"linear" => Some(Model::Linear),
+			_        => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Model::Luma   => "luma",
+			Model::Linear => "linear",
+		}
+	}
+
+	/// Build the lookup tables for each channel, with the channel weights
+	/// already applied, so the luminance is just the sum of the three.
+	///
+	/// Each table has an entry for every value the channel can have in the
+	/// given format.
+	fn tables(&self, format: &Format) -> Tables {
+		let (weights, linear) = match *self {
+			Model::Luma   => ([0.299, 0.587, 0.114], false),
+			Model::Linear => ([0.2126, 0.7152, 0.0722], true),
+		};
+
+		let table = |channel: Channel, weight: f32| {
+			let max = ((1 << channel.bits) - 1) as f32;
+
+			(0 .. 1 << channel.bits).map(|value| {
+				let mut c = value as f32 / max;
+
+				if linear {
+					c = if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+				}
+
+				c * weight
+			}).collect()
+		};
+
+		[table(format.red, weights[0]), table(format.green, weights[1]), table(format.blue, weights[2])]
+	}
+}
+
+/// Which statistic of the pixel luminances is used as the luminance.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum Metric {
+	/// The root mean square.
+	#[default]
+	Rms,
+
+	/// The arithmetic mean.
+	Mean,
+
+	/// The median.
+	Median,
+
+	/// The given percentile, between `0.0` and `1.0`.
+	Percentile(f32),
+
+	/// The contrast, as in the standard deviation.
+	Contrast,
+}
+
+impl Metric {
+	pub fn parse<T: AsRef<str>>(value: T, percentile: f32) -> Option<Metric> {
+		match value.as_ref() {
+			"rms"        => Some(Metric::Rms),
+			"mean"       => Some(Metric::Mean),
+			"median"     => Some(Metric::Median),
+			"percentile" => Some(Metric::Percentile(percentile)),
+			"contrast"   => Some(Metric::Contrast),
+			_            => None,
+		}
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Metric::Rms            => "rms",
+			Metric::Mean           => "mean",
+			Metric::Median         => "median",
+			Metric::Percentile(..) => "percentile",
+			Metric::Contrast       => "contrast",
+		}
+	}
+}
+
+/// How much each pixel counts towards the luminance.
+#[derive(PartialEq, Copy, Clone, Debug, Default)]
+pub enum Weighting {
+	/// Every pixel counts the same.
+	#[default]
+	Uniform,
+
+	/// Only the pixels within the focused window count.
+	Active,
+
+	/// Pixels count less the farther they are from the center.
+	Center,
+
+	/// The pixels within the focused window count fully, the rest of the screen
+	/// with the given weight.
+	Focus(f32),
+}
+
+impl Weighting {
+	pub fn parse<T: AsRef<str>>(value: T, rest: f32) -> Option<Weighting> {
+		match value.as_ref() {
+			"uniform" => Some(Weighting::Uniform),
+			"active"  => Some(Weighting::Active),
+			"center"  => Some(Weighting::Center),
+			"focus"   => Some(Weighting::Focus(rest)),
+			_         => None,
+		}
+	}
+
+	/// Check if the weighting depends on the focused window.
+	pub fn is_focused(&self) -> bool {
+		matches!(*self, Weighting::Active | Weighting::Focus(..))
+	}
+}
+
+/// How the luminance is smoothed over time before being used.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Smoothing {
+	/// Time constant in milliseconds when the content gets brighter.
+	pub rise: u64,
+
+	/// Time constant in milliseconds when the content gets darker.
+	pub fall: u64,
+
+	/// Changes smaller than this are ignored.
+	pub band: f32,
+}
+
+impl Default for Smoothing {
+	fn default() -> Self {
+		Smoothing {
+			rise: 0,
+			fall: 0,
+			band: 0.0,
+		}
+	}
+}
+
+/// An exponential moving average of the luminance with a dead-band.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Smoother {
+	/// The last sample, the smoothed value and the value in use.
+	state:   Option<(f32, f32, f32)>,
+	updated: Option<Instant>,
+}
+
+impl Smoother {
+	/// Feed a new luminance sample, returning the luminance to use.
+	pub fn sample(&mut self, settings: &Smoothing, value: f32, now: Instant) -> f32 {
+		let (smoothed, target) = if let (Some((_, smoothed, target)), Some(updated)) = (self.state, self.updated) {
+			let tau   = if value > smoothed { settings.rise } else { settings.fall };
+			let alpha = if tau == 0 {
+				1.0
+			}
+			else {
+				let elapsed = now.saturating_duration_since(updated);
+				1.0 - (-(elapsed.as_secs_f32() * 1000.0) / tau as f32).exp()
+			};
+
+			let smoothed = smoothed + alpha * (value - smoothed);
+
+			// Only move the value in use once it gets far enough from the current one.
+			if (smoothed - target).abs() >= settings.band {
+				(smoothed, smoothed)
+			}
+			else {
+				(smoothed, target)
+			}
+		}
+		else {
+			(value, value)
+		};
+
+		self.state   = Some((value, smoothed, target));
+		self.updated = Some(now);
+
+		target
+	}
+
+	/// Check whether the value in use has caught up with the samples, if not
+	/// more samples are needed even if the content doesn't change.
+	pub fn is_settled(&self, settings: &Smoothing) -> bool {
+		if let Some((value, smoothed, target)) = self.state {
+			(value - target).abs() < settings.band || (value - smoothed).abs() < 1.0 / 255.0
+		}
+		else {
+			true
+		}
+	}
+
+	/// Forget the past samples.
+	pub fn reset(&mut self) {
+		self.state   = None;
+		self.updated = None;
+	}
+}
+
+/// The statistics of the sampled pixels within a tile.
+#[derive(Copy, Clone)]
+struct Tile {
+	count:     u16,
+	sum:       f32,
+	squares:   f32,
+	histogram: [u16; BINS],
+}
+
+/// A section of the screen with its own total luminance.
+#[derive(Copy, Clone, Debug)]
+struct Area {
+	x:      u32,
+	y:      u32,
+	width:  u32,
+	height: u32,
+
+	/// The weighted sum of the luminances.
+	sum: u64,
+
+	/// The weighted sum of the squared luminances.
+	squares: u64,
+
+	/// The weighted sum of the pixels for each luminance bin.
+	histogram: [u64; BINS],
+
+	/// The weighted sum of the pixels.
+	weight: u64,
+
+	/// Whether the focused window is within the area, if it's not the focused
+	/// weightings fall back to uniform.
+	focused: bool,
+}
+
+/// The area covered by an output.
+struct Region {
+	output: display::Output,
+	area:   Area,
+}
+
+const PRECISION: f32 = 1_000_000.0;
+const BINS:      usize = 64;
+const TILE:      u32 = 16;
+
+/// Check if the point is within the rectangle.
+fn inside(rect: xcb::Rectangle, x: u32, y: u32) -> bool {
+	let (x, y) = (x as i32, y as i32);
+
+	x >= i32::from(rect.x()) && x < i32::from(rect.x()) + i32::from(rect.width()) &&
+	y >= i32::from(rect.y()) && y < i32::from(rect.y()) + i32::from(rect.height())
+}
+
+/// Check if the rectangles are the same.
+fn same(a: &xcb::Rectangle, b: &xcb::Rectangle) -> bool {
+	a.x() == b.x() && a.y() == b.y() && a.width() == b.width() && a.height() == b.height()
+}
+
+/// The histogram bin for the given luminance.
+fn bin(l: f32) -> usize {
+	cmp::min((l * BINS as f32) as usize, BINS - 1)
+}
+
+impl Default for Tile {
+	fn default() -> Self {
+		Tile {
+			count:     0,
+			sum:       0.0,
+			squares:   0.0,
+			histogram: [0; BINS],
+		}
+	}
+}
+
+impl Tile {
+	/// Compute the statistics of a tile from an image section in the given
+	/// format, sampling every `stride` pixel on both axes.
+	fn measure(data: &[u8], pitch: usize, width: usize, height: usize, stride: usize, format: &Format, tables: &Tables) -> Self {
+		let mut tile = Tile::default();
+		let mut row  = [0.0; TILE as usize];
+
+		for y in (0 .. height).step_by(stride) {
+			let pixels = &data[y * pitch .. y * pitch + width * format.bytes];
+			let mut n  = 0;
+
+			// First convert the whole row, then accumulate it, so each loop stays
+			// simple enough for the compiler to unroll and vectorize.
+			for (l, pixel) in row.iter_mut().zip(pixels.chunks_exact(format.bytes).step_by(stride)) {
+				let pixel = format.pixel(pixel);
+
+				*l = tables[0][format.red.get(pixel)] + tables[1][format.green.get(pixel)] + tables[2][format.blue.get(pixel)];
+				n += 1;
+			}
+
+			for &l in &row[.. n] {
+				tile.sum     += l;
+				tile.squares += l * l;
+			}
+
+			for &l in &row[.. n] {
+				tile.histogram[bin(l)] += 1;
+			}
+
+			tile.count += n as u16;
+		}
+
+		tile
+	}
+}
+
+impl Area {
+	fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+		Area {
+			x, y, width, height,
+
+			sum:       0,
+			squares:   0,
+			histogram: [0; BINS],
+			weight:    0,
+			focused:   false,
+		}
+	}
+
+	fn contains(&self, x: u32, y: u32) -> bool {
+		x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+	}
+
+	/// Add a tile to the totals.
+	fn add(&mut self, tile: &Tile, w: f32) {
+		self.sum     += (tile.sum * w * PRECISION) as u64;
+		self.squares += (tile.squares * w * PRECISION) as u64;
+		self.weight  += (f32::from(tile.count) * w * PRECISION) as u64;
+
+		for (total, &count) in self.histogram.iter_mut().zip(tile.histogram.iter()) {
+			*total += (f32::from(count) * w * PRECISION) as u64;
+		}
+	}
+
+	/// Remove a tile from the totals, the weight must be the same it was added
+	/// with.
+	fn remove(&mut self, tile: &Tile, w: f32) {
+		self.sum     -= (tile.sum * w * PRECISION) as u64;
+		self.squares -= (tile.squares * w * PRECISION) as u64;
+		self.weight  -= (f32::from(tile.count) * w * PRECISION) as u64;
+
+		for (total, &count) in self.histogram.iter_mut().zip(tile.histogram.iter()) {
+			*total -= (f32::from(count) * w * PRECISION) as u64;
+		}
+	}
+
+	/// The weight of the given point within the area.
+	fn weight(&self, weighting: Weighting, focus: Option<xcb::Rectangle>, x: u32, y: u32) -> f32 {
+		let inside = |rect| inside(rect, x, y);
+
+		match weighting {
+			Weighting::Uniform =>
+				1.0,
+
+			Weighting::Active | Weighting::Focus(..) if !self.focused =>
+				1.0,
+
+			Weighting::Active =>
+				if focus.map(inside).unwrap_or(false) { 1.0 } else { 0.0 },
+
+			Weighting::Focus(rest) =>
+				if focus.map(inside).unwrap_or(false) { 1.0 } else { rest },
+
+			// The distance from the center is normalized to the area so the weight
+			// goes from `1.0` in the center to `0.0` in the corners.
+			Weighting::Center => {
+				let dx = (x as f32 - self.x as f32 - self.width as f32 / 2.0) / (self.width as f32 / 2.0);
+				let dy = (y as f32 - self.y as f32 - self.height as f32 / 2.0) / (self.height as f32 / 2.0);
+
+				(1.0 - (dx.powi(2) + dy.powi(2)) / 2.0).max(0.0)
+			}
+		}
+	}
+
+	/// Get the luminance using the given metric.
+	fn luminance(&self, metric: Metric) -> f32 {
+		if self.weight == 0 {
+			return 0.0;
+		}
+
+		let weight = self.weight as f64;
+		let mean   = self.sum as f64 / weight;
+		let square = self.squares as f64 / weight;
+
+		let value = match metric {
+			Metric::Rms =>
+				square.sqrt(),
+
+			Metric::Mean =>
+				mean,
+
+			Metric::Median =>
+				self.percentile(0.5),
+
+			Metric::Percentile(p) =>
+				self.percentile(f64::from(p)),
+
+			// The variance can go slightly negative because of the rounding.
+			Metric::Contrast =>
+				(square - mean.powi(2)).max(0.0).sqrt(),
+		};
+
+		value as f32
+	}
+
+	/// Get the given percentile from the histogram, interpolating within the
+	/// bin it falls in.
+	fn percentile(&self, p: f64) -> f64 {
+		let target  = p * self.histogram.iter().sum::<u64>() as f64;
+		let mut acc = 0.0;
+
+		for (index, &count) in self.histogram.iter().enumerate() {
+			let count = count as f64;
+
+			if count > 0.0 && acc + count >= target {
+				return (index as f64 + (target - acc) / count) / BINS as f64;
+			}
+
+			acc += count;
+		}
+
+		1.0
+	}
+}
+
+impl Engine {
+	/// Create an engine for a buffer of the given size and format.
+	pub fn new(format: Format, width: u32, height: u32) -> Self {
+		let mut engine = Engine {
+			format,
+			width, height,
+
+			tiles:     Vec::new(),
+			dirty:     Vec::new(),
+			columns:   0,
+			stride:    1,
+			model:     Model::default(),
+			tables:    Model::default().tables(&format),
+			metric:    Metric::default(),
+			weighting: Weighting::default(),
+			focus:     None,
+			excluded:  Vec::new(),
+			total:     Area::new(0, 0, width, height),
+			regions:   Vec::new(),
+		};
+
+		engine.retile();
+		engine
+	}
+
+	/// Get the pixel format.
+	pub fn format(&self) -> &Format {
+		&self.format
+	}
+
+	/// Get the width.
+	pub fn width(&self) -> u32 {
+		self.width
+	}
+
+	/// Get the height.
+	pub fn height(&self) -> u32 {
+		self.height
+	}
+
+	/// Change the size, this resets all the tiles and regions.
+	pub fn resize(&mut self, width: u32, height: u32) {
+		self.width  = width;
+		self.height = height;
+		self.total  = Area::new(0, 0, width, height);
+		self.regions.clear();
+
+		self.retile();
+	}
+
+	/// Reset the tiles to cover the whole buffer.
+	fn retile(&mut self) {
+		self.columns = self.width.div_ceil(TILE);
+		self.tiles.clear();
+		self.tiles.resize((self.columns * self.height.div_ceil(TILE)) as usize, Tile::default());
+		self.dirty.clear();
+		self.dirty.resize(self.tiles.len(), false);
+	}
+
+	/// Change the output regions the buffer is split into.
+	pub fn layout<I: IntoIterator<Item = display::Output>>(&mut self, outputs: I) {
+		self.regions.clear();
+
+		for mut output in outputs {
+			// Clip the output to the buffer, it could be outside of it while the
+			// screen configuration is changing.
+			if output.x >= self.width || output.y >= self.height {
+				continue;
+			}
+
+			output.width  = cmp::min(output.width, self.width - output.x);
+			output.height = cmp::min(output.height, self.height - output.y);
+
+			let area = Area::new(output.x, output.y, output.width, output.height);
+			self.regions.push(Region { output, area });
+		}
+
+		self.reweigh();
+	}
+
+	/// Change the luminance model, returns whether it changed, in which case
+	/// the whole buffer has to be updated again.
+	pub fn model(&mut self, value: Model) -> bool {
+		if self.model != value {
+			self.model  = value;
+			self.tables = value.tables(&self.format);
+
+			true
+		}
+		else {
+			false
+		}
+	}
+
+	/// Change the luminance metric.
+	pub fn metric(&mut self, value: Metric) {
+		self.metric = value;
+	}
+
+	/// Change the sampling stride, only every `stride` pixel on both axes is
+	/// used, returns whether it changed, in which case the whole buffer has to
+	/// be updated again.
+	pub fn stride(&mut self, value: u32) -> bool {
+		let value = value.clamp(1, TILE);
+
+		if self.stride != value {
+			self.stride = value;
+
+			true
+		}
+		else {
+			false
+		}
+	}
+
+	/// Change the weighting.
+	pub fn weighting(&mut self, value: Weighting) {
+		if self.weighting != value {
+			self.weighting = value;
+			self.reweigh();
+		}
+	}
+
+	/// Change the focused window geometry.
+	pub fn focus(&mut self, value: Option<xcb::Rectangle>) {
+		let changed = match (self.focus, value) {
+			(Some(a), Some(b)) =>
+				!same(&a, &b),
+
+			(None, None) =>
+				false,
+
+			_ =>
+				true,
+		};
+
+		if changed {
+			self.focus = value;
+
+			if self.weighting.is_focused() {
+				self.reweigh();
+			}
+		}
+	}
+
+	/// Change the areas that don't count towards the luminance, returns
+	/// whether they changed.
+	pub fn exclude(&mut self, value: Vec<xcb::Rectangle>) -> bool {
+		if self.excluded.len() == value.len() && self.excluded.iter().zip(&value).all(|(a, b)| same(a, b)) {
+			return false;
+		}
+
+		self.excluded = value;
+		self.reweigh();
+
+		true
+	}
+
+	/// Check if the given point is within an excluded area.
+	fn is_excluded(&self, x: u32, y: u32) -> bool {
+		self.excluded.iter().any(|&rect| inside(rect, x, y))
+	}
+
+	/// The center of the given tile, which decides its weight and which areas
+	/// it belongs to.
+	fn center(&self, index: usize) -> (u32, u32) {
+		let x = (index as u32 % self.columns) * TILE;
+		let y = (index as u32 / self.columns) * TILE;
+
+		(x + (cmp::min(x + TILE, self.width) - x) / 2, y + (cmp::min(y + TILE, self.height) - y) / 2)
+	}
+
+	/// Recalculate the total luminances from the tiles.
+	fn reweigh(&mut self) {
+		let weighting = self.weighting;
+		let focus     = self.focus;
+
+		for index in 0 .. self.regions.len() + 1 {
+			let mut area = if index == 0 { self.total } else { self.regions[index - 1].area };
+
+			area.focused = focus.map(|rect| {
+				i32::from(rect.x()) < (area.x + area.width) as i32 && i32::from(rect.x()) + i32::from(rect.width()) > area.x as i32 &&
+				i32::from(rect.y()) < (area.y + area.height) as i32 && i32::from(rect.y()) + i32::from(rect.height()) > area.y as i32
+			}).unwrap_or(false);
+
+			area = Area { focused: area.focused, .. Area::new(area.x, area.y, area.width, area.height) };
+
+			for (i, tile) in self.tiles.iter().enumerate() {
+				let (x, y) = self.center(i);
+
+				if area.contains(x, y) && !self.is_excluded(x, y) {
+					area.add(tile, area.weight(weighting, focus, x, y));
+				}
+			}
+
+			if index == 0 {
+				self.total = area;
+			}
+			else {
+				self.regions[index - 1].area = area;
+			}
+		}
+	}
+
+	/// Get the current outputs.
+	pub fn outputs(&self) -> impl Iterator<Item = &display::Output> {
+		self.regions.iter().map(|r| &r.output)
+	}
+
+	/// Get the output with the given ID.
+	pub fn output(&self, id: xcb::randr::Output) -> Option<&display::Output> {
+		self.outputs().find(|o| o.id == id)
+	}
+
+	/// Align a section to the tiles, so every tile it touches is recomputed as
+	/// a whole, returns `None` if the section is empty.
+	pub fn align(&self, x: u32, y: u32, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+		let left   = x / TILE * TILE;
+		let top    = y / TILE * TILE;
+		let right  = cmp::min((x + width).div_ceil(TILE) * TILE, self.width);
+		let bottom = cmp::min((y + height).div_ceil(TILE) * TILE, self.height);
+
+		if left >= right || top >= bottom {
+			None
+		}
+		else {
+			Some((left, top, right - left, bottom - top))
+		}
+	}
+
+	/// Mark the tiles touched by the given section as changed, so they're
+	/// returned by `dirty`.
+	pub fn mark(&mut self, x: u32, y: u32, width: u32, height: u32) {
+		if let Some((x, y, width, height)) = self.align(x, y, width, height) {
+			for ty in y / TILE .. (y + height).div_ceil(TILE) {
+				for tx in x / TILE .. (x + width).div_ceil(TILE) {
+					self.dirty[(tx + ty * self.columns) as usize] = true;
+				}
+			}
+		}
+	}
+
+	/// Take the sections covering the changed tiles, each run of changed tiles
+	/// in a row is a section, and runs spanning the same columns in consecutive
+	/// rows are merged.
+	pub fn dirty(&mut self) -> Vec<(u32, u32, u32, u32)> {
+		let mut result: Vec<(u32, u32, u32, u32)> = Vec::new();
+		let mut above  = Vec::<usize>::new();
+		let rows       = self.height.div_ceil(TILE);
+
+		for ty in 0 .. rows {
+			let mut current = Vec::new();
+			let mut tx      = 0;
+
+			while tx < self.columns {
+				if !self.dirty[(tx + ty * self.columns) as usize] {
+					tx += 1;
+					continue;
+				}
+
+				let start = tx;
+				while tx < self.columns && self.dirty[(tx + ty * self.columns) as usize] {
+					self.dirty[(tx + ty * self.columns) as usize] = false;
+					tx += 1;
+				}
+
+				let x      = start * TILE;
+				let y      = ty * TILE;
+				let width  = cmp::min(tx * TILE, self.width) - x;
+				let height = cmp::min(y + TILE, self.height) - y;
+
+				// Extend the section right above if it spans the same columns.
+				if let Some(&index) = above.iter().find(|&&i| result[i].0 == x && result[i].2 == width) {
+					result[index].3 += height;
+					current.push(index);
+				}
+				else {
+					current.push(result.len());
+					result.push((x, y, width, height));
+				}
+			}
+
+			above = current;
+		}
+
+		result
+	}
+
+	/// Update the luminance values from the pixels of an aligned section, the
+	/// data starts at the top left corner of the section and each row is
+	/// `pitch` bytes long.
+	pub fn update(&mut self, data: &[u8], pitch: usize, x: u32, y: u32, width: u32, height: u32) {
+		let (right, bottom) = (x + width, y + height);
+
+		for ty in y / TILE .. bottom.div_ceil(TILE) {
+			for tx in x / TILE .. right.div_ceil(TILE) {
+				let (xx, yy) = (tx * TILE - x, ty * TILE - y);
+				let offset   = yy as usize * pitch + xx as usize * self.format.bytes;
+
+				let tile = Tile::measure(&data[offset ..], pitch,
+					cmp::min(TILE, width - xx) as usize, cmp::min(TILE, height - yy) as usize,
+					self.stride as usize, &self.format, &self.tables);
+
+				self.replace((tx + ty * self.columns) as usize, tile);
+			}
+		}
+	}
+
+	/// Replace the given tile, updating the totals of the areas it belongs to.
+	fn replace(&mut self, index: usize, tile: Tile) {
+		// The totals are kept as `u64` to avoid incremental precision errors
+		// because of the repeated operations, the weight of a tile only changes
+		// in `reweigh` so the same value is always subtracted as was added.
+		let (x, y)    = self.center(index);
+		let before    = self.tiles[index];
+		let weighting = self.weighting;
+		let focus     = self.focus;
+		let excluded  = self.is_excluded(x, y);
+
+		for area in Some(&mut self.total).into_iter().chain(self.regions.iter_mut().map(|r| &mut r.area)) {
+			if area.contains(x, y) && !excluded {
+				let w = area.weight(weighting, focus, x, y);
+
+				area.remove(&before, w);
+				area.add(&tile, w);
+			}
+		}
+
+		self.tiles[index] = tile;
+		self.dirty[index] = false;
+	}
+
+	/// Get the luminance.
+	pub fn luminance(&self) -> f32 {
+		self.total.luminance(self.metric)
+	}
+
+	/// Get the luminance of the given output, falling back to the whole buffer
+	/// if there's no such output.
+	pub fn luminance_of(&self, output: Option<xcb::randr::Output>) -> f32 {
+		if let Some(region) = output.and_then(|id| self.regions.iter().find(|r| r.output.id == id)) {
+			region.area.luminance(self.metric)
+		}
+		else {
+			self.luminance()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn visual(depth: u8, bpp: u8, (red, green, blue): (u32, u32, u32), msb: bool) -> display::Visual {
+		display::Visual { depth, bpp, red, green, blue, msb }
+	}
+
+	/// Measure a single row of pixels, returning the mean luminance.
+	fn mean(format: &Format, data: &[u8]) -> f32 {
+		let tables = Model::Luma.tables(format);
+		let width  = data.len() / format.bytes;
+		let tile   = Tile::measure(data, data.len(), width, 1, 1, format, &tables);
+
+		assert_eq!(tile.count as usize, width);
+		tile.sum / f32::from(tile.count)
+	}
+
+	fn lengths(format: &Format) -> [usize; 3] {
+		let tables = Model::Luma.tables(format);
+		[tables[0].len(), tables[1].len(), tables[2].len()]
+	}
+
+	fn close(a: f32, b: f32) -> bool {
+		(a - b).abs() < 1e-4
+	}
+
+	#[test]
+	fn rgb565() {
+		let format = Format::new(&visual(16, 16, (0xf800, 0x07e0, 0x001f), false)).unwrap();
+
+		assert_eq!(lengths(&format), [32, 64, 32]);
+		assert!(close(mean(&format, &[0xff, 0xff]), 1.0));
+		assert!(close(mean(&format, &[0x00, 0x00]), 0.0));
+		assert!(close(mean(&format, &[0x00, 0xf8]), 0.299));
+		assert!(close(mean(&format, &[0xe0, 0x07]), 0.587));
+		assert!(close(mean(&format, &[0x1f, 0x00]), 0.114));
+	}
+
+	#[test]
+	fn packed24() {
+		let format = Format::new(&visual(24, 24, (0xff0000, 0x00ff00, 0x0000ff), false)).unwrap();
+
+		// Red, then green, then blue, three bytes each.
+		assert!(close(mean(&format, &[0x00, 0x00, 0xff, 0x00, 0x00, 0x00]), 0.299 / 2.0));
+		assert!(close(mean(&format, &[0x00, 0xff, 0x00, 0xff, 0x00, 0x00]), (0.587 + 0.114) / 2.0));
+	}
+
+	#[test]
+	fn bgrx32() {
+		let format = Format::new(&visual(24, 32, (0xff0000, 0x00ff00, 0x0000ff), false)).unwrap();
+
+		assert_eq!(lengths(&format), [256, 256, 256]);
+		assert!(close(mean(&format, &[0x80, 0x80, 0x80, 0xff]), 128.0 / 255.0));
+		assert!(close(mean(&format, &[0x00, 0x00, 0xff, 0x00]), 0.299));
+	}
+
+	#[test]
+	fn xrgb32_msb() {
+		let format = Format::new(&visual(24, 32, (0xff0000, 0x00ff00, 0x0000ff), true)).unwrap();
+
+		assert!(close(mean(&format, &[0x00, 0xff, 0x00, 0x00]), 0.299));
+		assert!(close(mean(&format, &[0x00, 0x00, 0x00, 0xff]), 0.114));
+	}
+
+	#[test]
+	fn deep30() {
+		let format = Format::new(&visual(30, 32, (0x3ff0_0000, 0x000f_fc00, 0x0000_03ff), false)).unwrap();
+
+		assert_eq!(lengths(&format), [1024, 1024, 1024]);
+		assert!(close(mean(&format, &0x3fff_ffffu32.to_le_bytes()), 1.0));
+		assert!(close(mean(&format, &0x0000_03ffu32.to_le_bytes()), 0.114));
+		assert!(close(mean(&format, &0x2000_0000u32.to_le_bytes()), 0.299 * 512.0 / 1023.0));
+	}
+
+	#[test]
+	fn stride_and_pitch() {
+		let format = Format::new(&visual(24, 32, (0xff0000, 0x00ff00, 0x0000ff), false)).unwrap();
+		let tables = Model::Luma.tables(&format);
+
+		// A 2x2 image of white and black pixels, with each row padded to 12 bytes.
+		let data = [
+			0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0xaa, 0xaa, 0xaa,
+			0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0x00, 0xaa, 0xaa, 0xaa, 0xaa,
+		];
+
+		let tile = Tile::measure(&data, 12, 2, 2, 1, &format, &tables);
+		assert_eq!(tile.count, 4);
+		assert!(close(tile.sum, 2.0));
+
+		let tile = Tile::measure(&data, 12, 2, 2, 2, &format, &tables);
+		assert_eq!(tile.count, 1);
+		assert!(close(tile.sum, 1.0));
+	}
+
+	#[test]
+	fn unsupported() {
+		// Pseudo color.
+		assert!(Format::new(&visual(8, 8, (0, 0, 0), false)).is_none());
+
+		// Non contiguous mask.
+		assert!(Format::new(&visual(24, 32, (0xf0f000, 0x000f00, 0x0000ff), false)).is_none());
+	}
+
+	/// Build an RGB buffer filling each half of the width with a gray level.
+	fn halves(width: u32, height: u32, left: u8, right: u8) -> Vec<u8> {
+		let mut data = Vec::new();
+
+		for _ in 0 .. height {
+			for x in 0 .. width {
+				let v = if x < width / 2 { left } else { right };
+				data.extend_from_slice(&[v, v, v]);
+			}
+		}
+
+		data
+	}
+
+	fn output(id: u32, x: u32, width: u32, height: u32) -> display::Output {
+		display::Output { id, name: format!("OUT-{}", id), x, y: 0, width, height }
+	}
+
+	#[test]
+	fn engine_metrics() {
+		let mut engine = Engine::new(Format::rgb(), 64, 32);
+		engine.update(&halves(64, 32, 255, 0), 64 * 3, 0, 0, 64, 32);
+
+		assert!(close(engine.luminance(), 0.5f32.sqrt()));
+
+		engine.metric(Metric::Mean);
+		assert!(close(engine.luminance(), 0.5));
+
+		engine.metric(Metric::Contrast);
+		assert!(close(engine.luminance(), 0.5));
+
+		engine.metric(Metric::Percentile(0.9));
+		assert!(engine.luminance() > 0.9);
+	}
+
+	#[test]
+	fn engine_regions() {
+		let mut engine = Engine::new(Format::rgb(), 64, 32);
+		engine.layout(vec![output(1, 0, 32, 32), output(2, 32, 32, 32), output(3, 64, 32, 32)]);
+		engine.update(&halves(64, 32, 255, 0), 64 * 3, 0, 0, 64, 32);
+		engine.metric(Metric::Mean);
+
+		// Outputs outside of the buffer are dropped.
+		assert_eq!(engine.outputs().count(), 2);
+
+		assert!(close(engine.luminance_of(Some(1)), 1.0));
+		assert!(close(engine.luminance_of(Some(2)), 0.0));
+		assert!(close(engine.luminance_of(Some(3)), 0.5));
+		assert!(close(engine.luminance_of(None), 0.5));
+	}
+
+	#[test]
+	fn engine_update_section() {
+		let mut engine = Engine::new(Format::rgb(), 64, 32);
+		engine.metric(Metric::Mean);
+		engine.update(&halves(64, 32, 0, 0), 64 * 3, 0, 0, 64, 32);
+		assert!(close(engine.luminance(), 0.0));
+
+		// Unaligned sections grow to cover whole tiles.
+		let (x, y, width, height) = engine.align(20, 4, 8, 8).unwrap();
+		assert_eq!((x, y, width, height), (16, 0, 16, 16));
+		assert!(engine.align(64, 0, 16, 16).is_none());
+
+		// Replacing a quarter of the buffer with white, twice, only counts once.
+		for _ in 0 .. 2 {
+			engine.update(&halves(32, 16, 255, 255), 32 * 3, 0, 0, 32, 16);
+		}
+
+		assert!(close(engine.luminance(), 0.25));
+	}
+
+	#[test]
+	fn engine_update_offset() {
+		let mut engine = Engine::new(Format::rgb(), 64, 32);
+		engine.metric(Metric::Mean);
+		engine.update(&halves(64, 32, 0, 0), 64 * 3, 0, 0, 64, 32);
+
+		// A section away from the origin is covered up to its own far edges, not
+		// up to its size counted from the origin.
+		engine.update(&halves(16, 16, 255, 255), 16 * 3, 48, 16, 16, 16);
+		assert!(close(engine.luminance(), 0.125));
+
+		engine.update(&halves(32, 16, 255, 255), 32 * 3, 16, 16, 32, 16);
+		assert!(close(engine.luminance(), 0.375));
+	}
+
+	#[test]
+	fn engine_weighting() {
+		let mut engine = Engine::new(Format::rgb(), 64, 32);
+		engine.metric(Metric::Mean);
+		engine.update(&halves(64, 32, 255, 0), 64 * 3, 0, 0, 64, 32);
+
+		// Only the focused window counts.
+		engine.weighting(Weighting::Active);
+		engine.focus(Some(xcb::Rectangle::new(0, 0, 32, 32)));
+		assert!(close(engine.luminance(), 1.0));
+
+		engine.focus(Some(xcb::Rectangle::new(32, 0, 32, 32)));
+		assert!(close(engine.luminance(), 0.0));
+
+		// The rest of the screen counts a quarter.
+		engine.weighting(Weighting::Focus(0.25));
+		assert!(close(engine.luminance(), 0.25 / 1.25));
+
+		// Symmetric content is unaffected by the center weighting.
+		engine.weighting(Weighting::Center);
+		assert!(close(engine.luminance(), 0.5));
+	}
+
+	#[test]
+	fn engine_exclude() {
+		let mut engine = Engine::new(Format::rgb(), 64, 32);
+		engine.metric(Metric::Mean);
+		engine.update(&halves(64, 32, 255, 0), 64 * 3, 0, 0, 64, 32);
+
+		assert!(engine.exclude(vec![xcb::Rectangle::new(32, 0, 32, 32)]));
+		assert!(!engine.exclude(vec![xcb::Rectangle::new(32, 0, 32, 32)]));
+		assert!(close(engine.luminance(), 1.0));
+
+		// Updates within the excluded area are ignored.
+		engine.update(&halves(32, 32, 255, 255), 32 * 3, 32, 0, 32, 32);
+		assert!(close(engine.luminance(), 1.0));
+
+		assert!(engine.exclude(Vec::new()));
+		assert!(close(engine.luminance(), 1.0));
+	}
+
+	#[test]
+	fn engine_dirty() {
+		let mut engine = Engine::new(Format::rgb(), 64, 40);
+
+		// Two small changes in opposite corners stay apart.
+		engine.mark(0, 0, 4, 4);
+		engine.mark(60, 36, 4, 4);
+		assert_eq!(engine.dirty(), vec![(0, 0, 16, 16), (48, 32, 16, 8)]);
+		assert!(engine.dirty().is_empty());
+
+		// Runs spanning the same columns are merged across rows.
+		engine.mark(0, 0, 20, 40);
+		engine.mark(60, 16, 4, 4);
+		assert_eq!(engine.dirty(), vec![(0, 0, 32, 40), (48, 16, 16, 16)]);
+
+		// Updated tiles aren't changed anymore.
+		engine.mark(0, 0, 64, 16);
+		engine.update(&halves(64, 16, 0, 0), 64 * 3, 0, 0, 64, 16);
+		assert!(engine.dirty().is_empty());
+	}
+
+	#[test]
+	fn engine_stride() {
+		let mut engine = Engine::new(Format::rgb(), 64, 32);
+		engine.metric(Metric::Mean);
+
+		assert!(engine.stride(2));
+		assert!(!engine.stride(2));
+
+		engine.update(&halves(64, 32, 255, 0), 64 * 3, 0, 0, 64, 32);
+		assert!(close(engine.luminance(), 0.5));
+	}
+
+	#[test]
+	fn engine_stride_bound() {
+		// Noise over a gradient, so the skipped pixels differ from the measured
+		// ones.
+		let (width, height) = (256, 128);
+		let mut data        = Vec::new();
+		let mut seed        = 42u32;
+
+		for y in 0 .. height {
+			for x in 0 .. width {
+				seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+				let v = ((x + y) / 2 + (seed >> 24) / 2) as u8;
+				data.extend_from_slice(&[v, v, v]);
+			}
+		}
+
+		for &metric in &[Metric::Rms, Metric::Mean, Metric::Median, Metric::Contrast] {
+			let mut full = Engine::new(Format::rgb(), width, height);
+			full.metric(metric);
+			full.update(&data, width as usize * 3, 0, 0, width, height);
+
+			for &stride in &[2, 4] {
+				let mut engine = Engine::new(Format::rgb(), width, height);
+				engine.metric(metric);
+				engine.stride(stride);
+				engine.update(&data, width as usize * 3, 0, 0, width, height);
+
+				assert!((engine.luminance() - full.luminance()).abs() < 0.02,
+					"{:?} with stride {}: {} against {}", metric, stride, engine.luminance(), full.luminance());
+			}
+		}
+	}
+
+	#[test]
+	fn smoother() {
+		use std::time::Duration;
+
+		let settings     = Smoothing { rise: 100, fall: 1_000, band: 0.05 };
+		let mut smoother = Smoother::default();
+		let start        = Instant::now();
+
+		assert!(close(smoother.sample(&settings, 0.5, start), 0.5));
+		assert!(smoother.is_settled(&settings));
+
+		// Changes within the dead-band are ignored.
+		assert!(close(smoother.sample(&settings, 0.54, start + Duration::from_secs(10)), 0.5));
+		assert!(smoother.is_settled(&settings));
+
+		// Getting brighter is followed quickly.
+		let value = smoother.sample(&settings, 1.0, start + Duration::from_millis(10_100));
+		assert!(close(value, 0.54 + (1.0 - 0.54) * (1.0 - (-1.0f32).exp())));
+		assert!(!smoother.is_settled(&settings));
+
+		// Getting darker is followed slowly.
+		let value = smoother.sample(&settings, 0.0, start + Duration::from_millis(10_200));
+		assert!(value > 0.7);
+
+		smoother.reset();
+		assert!(close(smoother.sample(&settings, 0.0, start), 0.0));
+	}
+}
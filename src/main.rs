@@ -21,9 +21,14 @@ pub use error::Error;
 mod display;
 pub use display::Display;
 
+mod luminance;
+pub use luminance::Engine;
+
 mod screen;
 pub use screen::Screen;
 
+mod image;
+
 mod backlight;
 pub use backlight::Backlight;
 
@@ -33,13 +38,36 @@ pub use timer::Timer;
 mod interface;
 pub use interface::Interface;
 
+mod socket;
+
 mod observer;
 pub use observer::Observer;
 
 mod cache;
 pub use cache::Cache;
 
+mod history;
+pub use history::History;
+
+mod config;
+pub use config::Config;
+
+mod video;
+
+mod i3;
+pub use i3::I3;
+
+mod head;
+pub use head::Head;
+
+mod idle;
+pub use idle::Idle;
+
 use std::sync::Arc;
+use std::thread;
+use std::process;
+use std::cmp;
+use log::{error, info, warn};
 use env_logger;
 use clap::{ArgMatches, Arg, App, SubCommand};
 use channel::select;
@@ -47,12 +75,31 @@ use channel::select;
 fn main() {
 	env_logger::init();
 
-	let     display   = Arc::new(Display::open().expect("no display found"));
-	let mut backlight = backlight::open(display.clone()).expect("no backlight support");
-
 	let mut app = App::new("dux")
 		.version(env!("CARGO_PKG_VERSION"))
 		.author("meh. <meh@schizofreni.co>")
+		.arg(Arg::with_name("config")
+			.short("C")
+			.long("config")
+			.takes_value(true)
+			.global(true)
+			.help("The path to the configuration file."))
+		.arg(Arg::with_name("backend")
+			.short("B")
+			.long("backend")
+			.takes_value(true)
+			.global(true)
+			.help("The backlight backend, one of either `randr` or `sys`."))
+		.arg(Arg::with_name("display")
+			.long("display")
+			.takes_value(true)
+			.global(true)
+			.help("The X display to use (default is `$DISPLAY`)."))
+		.arg(Arg::with_name("screen")
+			.long("screen")
+			.takes_value(true)
+			.global(true)
+			.help("The X screen to use (default is the display's default one, or every one for the adaptive daemon)."))
 		.subcommand(SubCommand::with_name("get")
 			.about("Get the brightness percentage."))
 		.subcommand(SubCommand::with_name("set")
@@ -115,6 +162,14 @@ fn main() {
 				.long("threshold")
 				.takes_value(true)
 				.help("Minimum total number of pixels for damages to be collapsed (default is `160000` around `400x400`)."))
+			.arg(Arg::with_name("cooldown")
+				.long("cooldown")
+				.takes_value(true)
+				.help("Time in milliseconds after a manual change during which the luminance won't override it (default is 1000)."))
+			.arg(Arg::with_name("poll")
+				.long("poll")
+				.takes_value(true)
+				.help("Interval in milliseconds at which the screen is polled without DAMAGE (default is 1000)."))
 			.arg(Arg::with_name("time")
 				.short("t")
 				.long("time")
@@ -125,11 +180,25 @@ fn main() {
 				.long("step")
 				.takes_value(true)
 				.help("Step to increase the brightness by (default is 1.0)."))
+			.arg(Arg::with_name("metric")
+				.long("metric")
+				.takes_value(true)
+				.help("One of either `rms`, `mean`, `median`, `percentile` or `contrast` (default is `rms`)."))
+			.arg(Arg::with_name("weighting")
+				.short("w")
+				.long("weighting")
+				.takes_value(true)
+				.help("One of either `uniform`, `active`, `center` or `focus` (default is `uniform`)."))
 			.arg(Arg::with_name("cache")
 				.short("c")
 				.long("cache")
 				.takes_value(true)
 				.help("The path to the cache file."))
+			.arg(Arg::with_name("history")
+				.short("H")
+				.long("history")
+				.takes_value(true)
+				.help("The path to the history file."))
 			.arg(Arg::with_name("profile")
 				.short("p")
 				.long("profile")
@@ -152,14 +221,135 @@ fn main() {
 				.required(true)
 				.index(1)
 				.help("The profile name.")))
+		.subcommand(SubCommand::with_name("status")
+			.about("Show what the adaptive daemon is doing.")
+			.arg(Arg::with_name("json")
+				.short("j")
+				.long("json")
+				.help("Print the status as JSON.")))
+		.subcommand(SubCommand::with_name("watch")
+			.about("Show the changes of the adaptive daemon as they happen.")
+			.arg(Arg::with_name("json")
+				.short("j")
+				.long("json")
+				.help("Print the changes as JSON lines.")))
+		.subcommand(SubCommand::with_name("history")
+			.about("Show the brightness change history.")
+			.arg(Arg::with_name("history")
+				.short("H")
+				.long("history")
+				.takes_value(true)
+				.help("The path to the history file."))
+			.arg(Arg::with_name("count")
+				.short("n")
+				.long("count")
+				.takes_value(true)
+				.help("Number of most recent changes to show (default is 20, 0 shows all)."))
+			.arg(Arg::with_name("cause")
+				.short("c")
+				.long("cause")
+				.takes_value(true)
+				.help("Only show changes with the given cause, one of either `user`, `mode`, `profile` or `fade`."))
+			.arg(Arg::with_name("mode")
+				.short("m")
+				.long("mode")
+				.takes_value(true)
+				.help("Only show changes made in the given mode."))
+			.arg(Arg::with_name("json")
+				.short("j")
+				.long("json")
+				.help("Print the raw JSON lines.")))
+		.subcommand(SubCommand::with_name("analyze")
+			.about("Compute the luminance of an image file.")
+			.arg(Arg::with_name("IMAGE")
+				.required(true)
+				.index(1)
+				.help("The path to a PPM or PNG image."))
+			.arg(Arg::with_name("metric")
+				.long("metric")
+				.takes_value(true)
+				.help("The metric used to pick the brightness (default is `rms`)."))
+			.arg(Arg::with_name("weighting")
+				.short("w")
+				.long("weighting")
+				.takes_value(true)
+				.help("One of either `uniform` or `center` (default is `uniform`)."))
+			.arg(Arg::with_name("cache")
+				.short("c")
+				.long("cache")
+				.takes_value(true)
+				.help("The path to the cache file."))
+			.arg(Arg::with_name("profile")
+				.short("p")
+				.long("profile")
+				.takes_value(true)
+				.help("The profile name (default is `default`)."))
+			.arg(Arg::with_name("json")
+				.short("j")
+				.long("json")
+				.help("Print the result as JSON.")))
 		.subcommand(SubCommand::with_name("sync")
 			.about("Synchronize any backlight changes with the adaptive daemon."))
 		.subcommand(SubCommand::with_name("save")
 			.about("Force flush the cache to disk."))
+		.subcommand(SubCommand::with_name("reload")
+			.about("Reload the configuration file."))
 		.subcommand(SubCommand::with_name("stop")
 			.about("Stop adaptive brightness mode."));
 
 	let matches = app.clone().get_matches();
+
+	// The configuration is only loaded by the commands that use it, and an
+	// invalid one is reported instead of panicking.
+	let config = || {
+		match Config::open(matches.value_of("config")).and_then(|mut config| config.merge(&matches).map(|_| config)) {
+			Ok(config) =>
+				config,
+
+			Err(err) => {
+				eprintln!("invalid configuration: {}", err);
+				process::exit(1);
+			}
+		}
+	};
+
+	// These don't need a display nor a backlight.
+	match matches.subcommand() {
+		("history", Some(submatches)) =>
+			return history(submatches),
+
+		("analyze", Some(submatches)) =>
+			return analyze(submatches, config()),
+
+		("status", Some(submatches)) =>
+			return status(submatches),
+
+		("watch", Some(submatches)) =>
+			return watch(submatches),
+
+		("mode", Some(submatches)) =>
+			return Interface::mode(submatches.value_of("MODE").unwrap()).unwrap(),
+
+		("profile", Some(submatches)) =>
+			return Interface::profile(submatches.value_of("PROFILE").unwrap()).unwrap(),
+
+		("save", Some(_)) =>
+			return Interface::save().unwrap(),
+
+		("reload", Some(_)) =>
+			return Interface::reload().unwrap(),
+
+		("stop", Some(_)) =>
+			return Interface::stop().unwrap(),
+
+		_ => ()
+	}
+
+	let     config    = config();
+	let     screen    = matches.value_of("screen").map(|v| v.parse().expect("invalid screen"));
+	let     display   = Arc::new(Display::open(matches.value_of("display"), screen).expect("no display found"));
+	let mut backlight = backlight::open(display.clone(), config.backend).expect("no backlight support");
+
 	match matches.subcommand() {
 		("get", Some(submatches)) =>
 			get(submatches, backlight),
@@ -174,23 +364,11 @@ fn main() {
 			dec(submatches, backlight),
 
 		("adaptive", Some(submatches)) =>
-			adaptive(submatches, display, backlight),
-
-		("mode", Some(submatches)) =>
-			Interface::mode(submatches.value_of("MODE").unwrap()).unwrap(),
-
-		("profile", Some(submatches)) =>
-			Interface::profile(submatches.value_of("PROFILE").unwrap()).unwrap(),
+			adaptive(submatches, config, display),
 
 		("sync", Some(_)) =>
 			Interface::brightness(backlight.get().unwrap()).unwrap(),
 
-		("save", Some(_)) =>
-			Interface::save().unwrap(),
-
-		("stop", Some(_)) =>
-			Interface::stop().unwrap(),
-
 		_ =>
 			app.print_help().unwrap()
 	}
@@ -227,51 +405,310 @@ pub fn dec(matches: &ArgMatches, mut backlight: Box<dyn Backlight>) {
 		matches.value_of("steps").unwrap_or("0").parse().unwrap()).unwrap();
 }
 
-pub fn adaptive(matches: &ArgMatches, display: Arc<Display>, mut backlight: Box<dyn Backlight>) {
-	use std::time::{Duration, Instant};
+pub fn status(matches: &ArgMatches) {
+	let status = Interface::status().expect("adaptive daemon not running");
+
+	if matches.is_present("json") {
+		let mut result = fields(&status, interface::PROPERTIES);
+		result["profiles"] = status.profiles.clone().into();
 
-	let time      = matches.value_of("time").unwrap_or("5").parse().unwrap();
-	let step      = matches.value_of("step").unwrap_or("1.0").parse().unwrap();
-	let refresh   = matches.value_of("refresh").unwrap_or("500").parse().unwrap();
-	let threshold = if refresh > 0 {
-		matches.value_of("threshold").unwrap_or("160000").parse().unwrap()
+		println!("{}", result.dump());
 	}
 	else {
-		u64::max_value()
+		for &name in interface::PROPERTIES {
+			println!("{:<10}  {}", name.to_lowercase(), field(&status, name));
+		}
+
+		println!("{:<10}  {}", "profiles", status.profiles.join(", "));
+	}
+}
+
+pub fn watch(matches: &ArgMatches) {
+	Interface::watch(|status, names| {
+		if matches.is_present("json") {
+			let mut result = fields(status, names);
+			result["time"] = chrono::Local::now().to_rfc3339().into();
+
+			println!("{}", result.dump());
+		}
+		else {
+			let time = chrono::Local::now().format("%H:%M:%S");
+
+			for &name in names {
+				println!("{}  {:<10}  {}", time, name.to_lowercase(), field(status, name));
+			}
+		}
+	}).expect("adaptive daemon not running");
+}
+
+/// Format the given property for humans.
+fn field(status: &interface::Status, name: &str) -> String {
+	match name {
+		"Mode"       => status.mode.name().into(),
+		"Profile"    => status.profile.clone(),
+		"Brightness" => format!("{:.2}", status.brightness),
+		"Target"     => format!("{:.2}", status.target),
+		"Luminance"  => format!("{:.4}", status.luminance),
+		"Backend"    => status.backend.clone(),
+		"Reason"     => status.reason.clone(),
+		_            => String::new(),
+	}
+}
+
+/// Get the given properties as a JSON object.
+fn fields(status: &interface::Status, names: &[&str]) -> json::JsonValue {
+	let mut result = json::object!{};
+
+	for &name in names {
+		result[name.to_lowercase()] = match name {
+			"Brightness" => status.brightness.into(),
+			"Target"     => status.target.into(),
+			"Luminance"  => status.luminance.into(),
+			_            => field(status, name).into(),
+		};
+	}
+
+	result
+}
+
+/// Describe why a brightness value was picked.
+fn reason(cause: history::Cause, (mode, key): &(&str, json::JsonValue)) -> String {
+	if key.is_null() {
+		format!("{}: {}", cause.name(), mode)
+	}
+	else {
+		format!("{}: {} {}", cause.name(), mode, key.dump())
+	}
+}
+
+pub fn history(matches: &ArgMatches) {
+	let count   = matches.value_of("count").unwrap_or("20").parse::<usize>().unwrap();
+	let cause   = matches.value_of("cause").map(|v| history::Cause::parse(v).expect("unknown cause"));
+	let mode    = matches.value_of("mode");
+	let entries = History::entries(History::path(matches.value_of("history"))).unwrap();
+
+	for entry in History::filter(&entries, cause, mode, count) {
+		if matches.is_present("json") {
+			println!("{}", entry.dump());
+		}
+		else {
+			println!("{}  {:<7}  {:<10}  {:<9}  {:<30}  {:.2}",
+				entry["time"], entry["cause"], entry["profile"], entry["mode"],
+				entry["key"].dump(), entry["value"].as_f32().unwrap_or(0.0));
+		}
+	}
+}
+
+pub fn analyze(matches: &ArgMatches, mut config: Config) {
+	use luminance::Metric;
+
+	config.merge(matches).expect("invalid configuration");
+
+	let     image  = image::Image::open(matches.value_of("IMAGE").unwrap()).expect("cannot open image");
+	let mut engine = Engine::new(luminance::Format::rgb(), image.width, image.height);
+	let mut cache  = Cache::open(None, matches.value_of("cache")).unwrap();
+
+	engine.stride(config.luminance.stride);
+	engine.model(config.luminance.model);
+	engine.weighting(config.luminance.weighting);
+	engine.update(&image.data, image.width as usize * 3, 0, 0, image.width, image.height);
+
+	let percentile = if let Metric::Percentile(p) = config.luminance.metric { p } else { 0.9 };
+	let metrics    = [Metric::Rms, Metric::Mean, Metric::Median, Metric::Percentile(percentile), Metric::Contrast];
+	let values     = metrics.iter().map(|&metric| {
+		engine.metric(metric);
+		(metric.name(), engine.luminance())
+	}).collect::<Vec<_>>();
+
+	engine.metric(config.luminance.metric);
+	cache.profile(config.profile.clone());
+	cache.transient(config.transient);
+
+	let luminance  = engine.luminance();
+	let brightness = cache.get(cache::Mode::Luminance(luminance)).unwrap();
+
+	if matches.is_present("json") {
+		let mut result = json::object!{
+			"width"      => image.width,
+			"height"     => image.height,
+			"model"      => config.luminance.model.name(),
+			"metric"     => config.luminance.metric.name(),
+			"profile"    => config.profile.clone(),
+			"luminance"  => luminance,
+			"brightness" => brightness,
+			"metrics"    => json::object!{},
+		};
+
+		for &(name, value) in &values {
+			result["metrics"][name] = value.into();
+		}
+
+		println!("{}", result.dump());
+	}
+	else {
+		for &(name, value) in &values {
+			println!("{:<10}  {:.4}", name, value);
+		}
+
+		println!();
+
+		match brightness {
+			Some(value) =>
+				println!("brightness  {:.2}  (profile `{}`, metric `{}`)", value, config.profile, config.luminance.metric.name()),
+
+			None =>
+				println!("brightness  none  (profile `{}` has no luminance data)", config.profile),
+		}
+	}
+}
+
+pub fn adaptive(matches: &ArgMatches, mut config: Config, display: Arc<Display>) {
+	use std::time::{Duration, Instant};
+
+	config.merge(matches).expect("invalid configuration");
+
+	// Every screen is managed unless one was picked, the selected or default one
+	// comes first.
+	let mut displays = vec![display.clone()];
+
+	if !matches.is_present("screen") {
+		for screen in (0 .. display.screens()).filter(|&s| s != display.screen()) {
+			match Display::open(matches.value_of("display"), Some(screen)) {
+				Ok(value) =>
+					displays.push(Arc::new(value)),
+
+				Err(err) =>
+					warn!("could not open screen {}: {}", screen, err),
+			}
+		}
+	}
+
+	let mut heads = displays.into_iter().enumerate().filter_map(|(index, display)| {
+		let screen = display.screen();
+
+		match Head::open(display, &config, index == 0) {
+			Ok(head) =>
+				Some(head),
+
+			Err(err) => {
+				warn!("could not manage screen {}: {}", screen, err);
+				None
+			}
+		}
+	}).collect::<Vec<_>>();
+
+	if heads.iter().all(|h| h.backlights.is_empty()) {
+		panic!("no backlight support");
+	}
+
+	// The events of every screen are merged, tagged with the index of the head
+	// they belong to.
+	let observer = {
+		let (sender, receiver) = channel::unbounded();
+
+		for (index, head) in heads.iter().enumerate() {
+			let sender   = sender.clone();
+			let observer = Observer::spawn(head.display.clone()).unwrap();
+
+			thread::spawn(move || {
+				while let Ok(event) = observer.recv() {
+					if sender.send((index, event)).is_err() {
+						break;
+					}
+				}
+			});
+		}
+
+		receiver
 	};
 
 	let     interface = Interface::spawn().unwrap();
-	let     observer  = Observer::spawn(display.clone()).unwrap();
-	let     timer     = Timer::spawn(timer::Settings { save: 30, heartbeat: 300 }).unwrap();
-	let mut cache     = Cache::open(display.clone(), matches.value_of("cache")).unwrap();
-	let mut screen    = Screen::open(display.clone(), display.width(), display.height()).unwrap();
+	let     timer     = Timer::spawn(config.timer).unwrap();
+	let mut cache     = Cache::open(Some(display.clone()), matches.value_of("cache")).unwrap();
+	let mut history   = History::open(matches.value_of("history"), config.history).unwrap();
+
+	// The idle time is the same on every screen.
+	let idle = Idle::spawn(display.clone(), config.idle).unwrap_or_else(|err| {
+		if config.idle.dim > 0 || config.idle.off > 0 {
+			warn!("MIT-SCREEN-SAVER not available, idle dimming disabled: {}", err);
+		}
+
+		Idle::none()
+	});
 
-	if let Some(profile) = matches.value_of("profile") {
-		cache.profile(profile);
+	// The workspaces from the IPC take the place of the EWMH desktop.
+	let mut i3 = if config.desktop.source == config::Source::I3 {
+		I3::spawn(config.desktop.socket.clone()).unwrap_or_else(|err| {
+			warn!("i3 IPC not available, falling back to EWMH: {}", err);
+			I3::none()
+		})
 	}
+	else {
+		I3::none()
+	};
+
+	cache.profile(config.profile.clone());
+	cache.transient(config.transient);
 
-	let mut mode        = interface::Mode::parse(matches.value_of("mode").unwrap_or("luminance")).unwrap();
-	let mut active      = None;
-	let mut desktop     = 0;
+	let mut mode        = config.mode;
+	let mut workspaces  = Vec::<i3::Workspace>::new();
+	let mut named       = Vec::<cache::Desktop>::new();
 	let mut changed     = Instant::now() - Duration::from_secs(42);
-	let mut brightness  = 0.0;
 	let mut rated       = false;
+	let mut ruled       = false;
+	let mut away        = idle::State::Active;
+	let mut locked      = false;
 	let mut screensaver = false;
+	let mut decision    = String::new();
+	let mut brightness  = 0.0;
+	let mut profiles: Vec<String>;
+
+	// Report which capture paths are going to be used, the fallbacks work but
+	// they're noticeably more expensive.
+	for head in &heads {
+		let capabilities = head.display.capabilities();
+
+		if capabilities.shm {
+			info!("screen {}: capturing the screen through MIT-SHM", head.display.screen());
+		}
+		else {
+			warn!("screen {}: MIT-SHM not available, capturing the screen through GetImage", head.display.screen());
+		}
+
+		if capabilities.damage {
+			info!("screen {}: tracking screen changes through DAMAGE", head.display.screen());
+		}
+		else {
+			warn!("screen {}: DAMAGE not available, polling the screen every {}ms", head.display.screen(), config.adaptive.poll);
+		}
+	}
+
+	// Any screen without DAMAGE has to be polled.
+	let polling = heads.iter().any(|h| !h.display.capabilities().damage);
+
+	if polling {
+		timer.refresh(config.adaptive.poll).unwrap();
+	}
 
 	macro_rules! mode {
-		($value:expr) =>(
+		($value:expr, $head:expr, $output:expr) =>(
 			match $value {
 				interface::Mode::Manual =>
 					cache::Mode::Manual,
 
+				// With per output workspaces each backlight follows the workspace
+				// visible on its own output.
 				interface::Mode::Desktop =>
-					cache::Mode::Desktop(desktop),
+					cache::Mode::Desktop($head.output($output)
+						.and_then(|name| workspaces.iter().find(|w| w.visible && w.output == name))
+						.map(i3::Workspace::desktop)
+						.unwrap_or_else(|| $head.desktop.clone())),
 
 				interface::Mode::Window =>
-					cache::Mode::Window(active),
+					cache::Mode::Window($head.active),
 
 				interface::Mode::Luminance =>
-					cache::Mode::Luminance(screen.luminance()),
+					cache::Mode::Luminance($head.screen.luminance_of($output)),
 
 				interface::Mode::Time =>
 					cache::Mode::Time(chrono::Local::now()),
@@ -279,21 +716,303 @@ pub fn adaptive(matches: &ArgMatches, display: Arc<Display>, mut backlight: Box<
 		);
 	}
 
-	macro_rules! fade {
+	// Request a refresh unless one is already pending, when polling there's one
+	// at every tick anyway.
+	macro_rules! refresh {
 		($value:expr) => (
-			match $value {
-				Some(v) if v != brightness => {
-					brightness = v;
-					backlight::fade::by_step(backlight.as_mut(), v, step, time)
+			if !polling && !rated {
+				timer.refresh($value).unwrap();
+				rated = true;
+			}
+		);
+	}
+
+	// Whether the backlight is held at a value other than the adaptive one,
+	// because the user is idle or the session is locked.
+	macro_rules! held {
+		() => (
+			away != idle::State::Active || ((locked || screensaver) && config.lock.is_some())
+		);
+	}
+
+	// Recompute the excluded windows when windows come and go, it's only worth
+	// it when the luminance is in use and there are windows to exclude, the
+	// struts are tracked by the observer.
+	macro_rules! excluded {
+		($head:expr) => (
+			mode == interface::Mode::Luminance && !config.luminance.exclude.is_empty() && $head.exclude(&config)
+		);
+	}
+
+	// The profiles that can be picked, only looked for when the cache is saved
+	// or the configuration reloaded.
+	macro_rules! profiles {
+		() => ({
+			let mut names = cache.profiles();
+			names.extend(config.profiles.keys().cloned());
+			names.sort();
+			names.dedup();
+			names
+		});
+	}
+
+	// Expose what the first backlight is doing; the backlight is only read back
+	// when something other than a fade moved it, otherwise the value that was
+	// written is shown.
+	macro_rules! status {
+		(read) => ({
+			if let Some(head) = heads.iter_mut().find(|h| !h.backlights.is_empty()) {
+				brightness = head.backlights[0].get().unwrap_or(head.brightness[0]);
+			}
+
+			status!();
+		});
+
+		() => (
+			if let Some(head) = heads.iter().find(|h| !h.backlights.is_empty()) {
+				let output = head.backlights[0].output();
+
+				// The held values win over what the mode picked.
+				let reason = match (config.lock, away) {
+					(Some(_), _) if locked || screensaver =>
+						"lock".into(),
+
+					(_, idle::State::Off) =>
+						"idle: off".into(),
+
+					(_, idle::State::Dim) =>
+						"idle: dim".into(),
+
+					(_, idle::State::Active) =>
+						decision.clone(),
+				};
+
+				interface.update(interface::Status {
+					mode, reason,
+					brightness,
+					target:     head.brightness[0],
+					luminance:  head.screen.luminance_of(output),
+					profile:    cache.current().into(),
+					backend:    head.backlights[0].backend().name().into(),
+					profiles:   profiles.clone(),
+				});
+			}
+		);
+	}
+
+	// Move the backlight to the held value, or back to the adaptive one right
+	// away; the held values are never learned.
+	macro_rules! hold {
+		($was:expr) => ({
+			for head in &mut heads {
+				for (light, current) in head.backlights.iter_mut().zip(head.brightness.iter_mut()) {
+					// Keep the value it's at, to go back to it later.
+					if !$was {
+						*current = light.get().unwrap_or(*current);
+					}
+
+					let target = match (config.lock, away) {
+						(Some(value), _) if locked || screensaver =>
+							Some(value),
+
+						(_, idle::State::Off) =>
+							Some(0.0),
+
+						(_, idle::State::Dim) =>
+							Some(current.min(config.idle.level)),
+
+						(_, idle::State::Active) =>
+							None,
+					};
+
+					let result = if let Some(value) = target {
+						backlight::fade::by_step(light.as_mut(), value, config.adaptive.step, config.adaptive.time)
+					}
+					else {
+						light.set(*current)
+					};
+
+					if let Err(err) = result {
+						error!("could not change the backlight: {}", err);
+					}
+				}
+			}
+
+			status!(read);
+		});
+	}
+
+	// Journal a brightness change; the journal is only informative, so failing
+	// to write it doesn't stop anything.
+	macro_rules! record {
+		($cause:expr, $output:expr, $description:expr, $value:expr) => (
+			match $description {
+				Ok(description) => {
+					decision = reason($cause, &description);
+
+					if let Err(err) = history.record($cause, cache.current(), $output, description, $value) {
+						warn!("could not write the history: {}", err);
+					}
+				}
+
+				Err(err) => {
+					warn!("could not describe the brightness change: {}", err);
+				}
+			}
+		);
+	}
+
+	macro_rules! fade {
+		($cause:expr) => ({
+			let mut result = Ok(());
+			let settings   = config.settings(cache.current());
+			let smoothing  = settings.smoothing;
+			let first      = heads.iter().position(|h| !h.backlights.is_empty());
+
+			for (number, head) in heads.iter_mut().enumerate() {
+				// While watching media the luminance can be ignored, depending on the
+				// profile.
+				let policy = if mode == interface::Mode::Luminance && (head.fullscreen || head.detector.is_active()) {
+					settings.media
+				}
+				else {
+					config::Policy::Follow
+				};
+
+				// Every backlight is handled on its own, so in luminance mode each one
+				// follows the contents of its own output.
+				for index in 0 .. head.backlights.len() {
+					let output = head.backlights[index].output();
+					let key    = match policy {
+						config::Policy::Freeze =>
+							continue,
+
+						config::Policy::Fixed(..) =>
+							None,
+
+						config::Policy::Window =>
+							Some(cache::Mode::Window(head.active)),
+
+						config::Policy::Follow if mode == interface::Mode::Luminance =>
+							Some(cache::Mode::Luminance(head.smoothers[index].sample(&smoothing, head.screen.luminance_of(output), Instant::now()))),
+
+						config::Policy::Follow =>
+							Some(mode!(mode, head, output)),
+					};
+
+					let value = match (policy, &key) {
+						(config::Policy::Fixed(value), _) =>
+							Some(value),
+
+						// The window can be gone by the time it's looked up, the brightness is
+						// left alone then.
+						(_, Some(key)) => match cache.get(key.clone()) {
+							Ok(value) =>
+								value,
+
+							Err(err) => {
+								warn!("could not look up the brightness: {}", err);
+								None
+							}
+						},
+
+						(_, None) =>
+							None,
+					};
+
+					match value {
+						Some(v) if v != head.brightness[index] => {
+							let description = if let Some(key) = key {
+								cache.describe(key)
+							}
+							else {
+								Ok(("fixed", json::JsonValue::Null))
+							};
+
+							head.brightness[index] = v;
+							record!($cause, head.output(output), description, v);
+
+							// While held the value is only applied once the user is back.
+							if !held!() {
+								let written = backlight::fade::by_step(head.backlights[index].as_mut(), v, config.adaptive.step, config.adaptive.time);
+
+								if written.is_ok() && index == 0 && Some(number) == first {
+									brightness = v;
+								}
+
+								result = result.and(written);
+							}
+						}
+
+						_ => ()
+					}
+				}
+
+				// The smoothed luminance has to keep moving even if the screen doesn't
+				// change anymore.
+				if policy == config::Policy::Follow && mode == interface::Mode::Luminance &&
+					head.smoothers.iter().any(|s| !s.is_settled(&smoothing))
+				{
+					refresh!(cmp::max(config.adaptive.refresh, 50));
+				}
+
+				// Check again later whether the video stopped, since the damages do.
+				if head.detector.is_active() {
+					refresh!(1_000);
 				}
+			}
+
+			status!();
+			result
+		})
+	}
+
+	// Switch to the profile for the connected outputs, if a rule matches, and
+	// back to the configured one when the rule stops matching; returns whether
+	// the profile changed.
+	macro_rules! rules {
+		() => ({
+			let connected = heads.iter()
+				.flat_map(|h| h.screen.outputs().map(|o| o.name.clone()))
+				.collect::<Vec<_>>();
+
+			let matched = config.rule(&connected).map(String::from);
+			let profile = match matched {
+				Some(ref name) =>
+					Some(name.clone()),
+
+				None if ruled =>
+					Some(config.profile.clone()),
 
-				_ => {
-					Ok(())
+				None =>
+					None,
+			};
+
+			ruled = matched.is_some();
+
+			match profile {
+				Some(name) if name != cache.current() => {
+					info!("switching to profile `{}` for outputs {:?}", name, connected);
+					cache.profile(name);
+
+					true
 				}
+
+				_ =>
+					false
 			}
-		)
+		})
+	}
+
+	for head in &mut heads {
+		head.exclude(&config);
 	}
 
+	rules!();
+
+	profiles = profiles!();
+	status!(read);
+
 	loop {
 		select! {
 			recv(timer) -> event => {
@@ -301,23 +1020,40 @@ pub fn adaptive(matches: &ArgMatches, display: Arc<Display>, mut backlight: Box<
 					timer::Event::Refresh => {
 						rated = false;
 
-						if mode == interface::Mode::Luminance {
-							screen.flush().unwrap();
+						for head in &mut heads {
+							if head.detector.check() {
+								info!("screen {}: video stopped", head.display.screen());
+							}
+
+							// Without DAMAGE the whole screen is fetched again at every tick.
+							if !head.display.capabilities().damage && mode == interface::Mode::Luminance && !screensaver {
+								head.screen.poll().unwrap();
+							}
 
-							if changed.elapsed().as_secs() >= 1 {
-								fade!(cache.get(cache::Mode::Luminance(screen.luminance())).unwrap()).unwrap()
+							if mode == interface::Mode::Luminance {
+								head.screen.flush().unwrap();
 							}
 						}
+
+						if polling {
+							timer.refresh(config.adaptive.poll).unwrap();
+						}
+
+						if mode == interface::Mode::Luminance && changed.elapsed() >= Duration::from_millis(config.adaptive.cooldown) {
+							fade!(history::Cause::Fade).unwrap()
+						}
 					}
 
 					timer::Event::Heartbeat => {
 						if mode == interface::Mode::Time {
-							fade!(cache.get(cache::Mode::Time(chrono::Local::now())).unwrap()).unwrap();
+							fade!(history::Cause::Fade).unwrap();
 						}
 					}
 
 					timer::Event::Save => {
 						cache.save().unwrap();
+						profiles = profiles!();
+						status!();
 					}
 				}
 			},
@@ -325,21 +1061,92 @@ pub fn adaptive(matches: &ArgMatches, display: Arc<Display>, mut backlight: Box<
 			recv(interface) -> event => {
 				match event.unwrap() {
 					interface::Event::Mode(value) => {
+						if value != mode {
+							for head in &mut heads {
+								head.smoothers.iter_mut().for_each(luminance::Smoother::reset);
+
+								// The exclusions aren't kept up to date in the other modes.
+								if value == interface::Mode::Luminance {
+									head.exclude(&config);
+								}
+							}
+						}
+
 						mode = value;
-						fade!(cache.get(mode!(value)).unwrap()).unwrap();
+						fade!(history::Cause::Mode).unwrap();
 					}
 
 					interface::Event::Profile(name) => {
+						ruled = false;
 						cache.profile(name);
+						fade!(history::Cause::Profile).unwrap();
 					}
 					
 					interface::Event::Save => {
 						cache.save().unwrap();
+						profiles = profiles!();
+						status!();
 					}
 
+					// The current mode and profile are left alone, only the tunables are
+					// reloaded.
+					interface::Event::Reload => {
+						match config.reload().and_then(|mut value| value.merge(matches).map(|_| value)) {
+							Ok(value) => {
+								for head in &mut heads {
+									if value.backend != config.backend {
+										if let Err(err) = head.backlights(&value) {
+											error!("could not open the backlight: {}", err);
+										}
+									}
+
+									head.configure(&value).unwrap();
+									head.exclude(&value);
+								}
+
+								let was = held!();
+								timer.update(value.timer).unwrap();
+								idle.update(value.idle);
+								cache.transient(value.transient);
+								history = History::open(matches.value_of("history"), value.history).unwrap();
+								config  = value;
+
+								if was || held!() {
+									hold!(was);
+								}
+
+								// The backend could have changed too.
+								profiles = profiles!();
+								status!(read);
+							}
+
+							Err(err) => {
+								error!("could not reload the configuration: {}", err);
+							}
+						}
+					}
+
+					// Changes from the CLI are applied to the first backlight, so the
+					// value is learned for its output.
 					interface::Event::Brightness(value) => {
-						changed = Instant::now();
-						cache.set(mode!(mode), value).unwrap();
+						// The value is learned against the actual luminance, so start over
+						// from it.
+						for head in &mut heads {
+							head.smoothers.iter_mut().for_each(luminance::Smoother::reset);
+						}
+
+						if let Some(head) = heads.iter().find(|h| !h.backlights.is_empty()) {
+							let output = head.backlights[0].output();
+							changed    = Instant::now();
+
+							if let Err(err) = cache.set(mode!(mode, head, output), value) {
+								warn!("could not learn the brightness: {}", err);
+							}
+
+							record!(history::Cause::User, head.output(output), cache.describe(mode!(mode, head, output)), value);
+						}
+
+						status!(read);
 					}
 
 					interface::Event::Stop => {
@@ -347,52 +1154,264 @@ pub fn adaptive(matches: &ArgMatches, display: Arc<Display>, mut backlight: Box<
 					}
 
 					interface::Event::ScreenSaver(active) => {
+						let was     = held!();
 						screensaver = active;
+
+						if was || held!() {
+							hold!(was);
+						}
+					}
+
+					// Firmware often resets the backlight on resume, so the value from
+					// before sleeping is applied again.
+					interface::Event::Sleep(true, inhibitor) => {
+						cache.save().unwrap();
+
+						if !held!() {
+							for head in &mut heads {
+								for (light, current) in head.backlights.iter_mut().zip(head.brightness.iter_mut()) {
+									*current = light.get().unwrap_or(*current);
+								}
+							}
+						}
+
+						// Sleep can go on now.
+						drop(inhibitor);
+					}
+
+					interface::Event::Sleep(false, _) => {
+						for head in &mut heads {
+							if let Err(err) = head.screen.recapture() {
+								error!("screen {}: could not capture the screen again: {}", head.display.screen(), err);
+							}
+						}
+
+						if held!() {
+							hold!(true);
+						}
+						else {
+							for head in &mut heads {
+								for (light, &current) in head.backlights.iter_mut().zip(head.brightness.iter()) {
+									if let Err(err) = light.set(current) {
+										error!("could not restore the backlight: {}", err);
+									}
+								}
+							}
+
+							fade!(history::Cause::Fade).unwrap();
+							status!(read);
+						}
+					}
+
+					interface::Event::Locked(value) => {
+						let was = held!();
+						locked  = value;
+
+						if was || held!() {
+							hold!(was);
+						}
 					}
 				}
 			},
 
-			recv(observer) -> event => {
+			recv(idle) -> event => {
+				let was = held!();
+				away    = event.unwrap();
+
+				if was || held!() {
+					hold!(was);
+				}
+			},
+
+			recv(i3) -> event => {
 				match event.unwrap() {
-					observer::Event::Show(_) | observer::Event::Hide(_) | observer::Event::Change(_) => (),
+					i3::Event::Workspaces(list) => {
+						if let Some(focused) = list.iter().find(|w| w.focused) {
+							for head in &mut heads {
+								head.desktop = focused.desktop();
+							}
+						}
 
-					observer::Event::Desktop(id) => {
-						desktop = id;
+						let desktops = list.iter().map(|w| w.desktop()).collect::<Vec<_>>();
+
+						if desktops != named {
+							cache.migrate(&desktops);
+							named = desktops;
+						}
+
+						workspaces = list;
 
 						if mode == interface::Mode::Desktop {
-							fade!(cache.get(cache::Mode::Desktop(desktop)).unwrap()).unwrap();
+							fade!(history::Cause::Fade).unwrap();
+						}
+					}
+
+					i3::Event::Closed(err) => {
+						warn!("i3 IPC connection lost, falling back to EWMH: {}", err);
+
+						i3         = I3::none();
+						workspaces = Vec::new();
+
+						for head in &mut heads {
+							head.desktop = Observer::desktop(&head.display).unwrap_or_default();
+						}
+					}
+				}
+			},
+
+			recv(observer) -> event => {
+				let (index, event) = event.unwrap();
+				let head           = &mut heads[index];
+
+				match event {
+					observer::Event::Show(_) | observer::Event::Hide(_) => {
+						if excluded!(head) && changed.elapsed() >= Duration::from_millis(config.adaptive.cooldown) {
+							fade!(history::Cause::Fade).unwrap();
+						}
+					}
+
+					// The window changes are reported for the top level windows, which
+					// usually are the window manager frames, so just check the active
+					// window again.
+					observer::Event::Change(_) => {
+						let mut dirty = excluded!(head);
+
+						if config.luminance.weighting.is_focused() {
+							head.focus();
+							dirty = true;
+						}
+
+						if dirty && mode == interface::Mode::Luminance && changed.elapsed() >= Duration::from_millis(config.adaptive.cooldown) {
+							fade!(history::Cause::Fade).unwrap();
+						}
+					}
+
+					observer::Event::Struts(value) => {
+						head.struts = value;
+
+						if mode == interface::Mode::Luminance && config.luminance.struts && head.exclude(&config) &&
+							changed.elapsed() >= Duration::from_millis(config.adaptive.cooldown)
+						{
+							fade!(history::Cause::Fade).unwrap();
+						}
+					}
+
+					observer::Event::Fullscreen(value) => {
+						if value != head.fullscreen {
+							head.fullscreen = value;
+
+							if mode == interface::Mode::Luminance && changed.elapsed() >= Duration::from_millis(config.adaptive.cooldown) {
+								fade!(history::Cause::Fade).unwrap();
+							}
+						}
+					}
+
+					observer::Event::Desktop(value, desktops) => {
+						if workspaces.is_empty() {
+							head.desktop = value;
+
+							// The values learned before the desktops had names are moved
+							// over as soon as the names are known.
+							if desktops != named {
+								cache.migrate(&desktops);
+								named = desktops;
+							}
+						}
+
+						if mode == interface::Mode::Desktop {
+							fade!(history::Cause::Fade).unwrap();
+						}
+						else if excluded!(head) && changed.elapsed() >= Duration::from_millis(config.adaptive.cooldown) {
+							fade!(history::Cause::Fade).unwrap();
 						}
 					}
 
 					observer::Event::Active(value) => {
-						active = value;
+						head.active = value;
+						head.focus();
 
 						if mode == interface::Mode::Window {
-							fade!(cache.get(cache::Mode::Window(active)).unwrap()).unwrap();
+							fade!(history::Cause::Fade).unwrap();
+						}
+						else if mode == interface::Mode::Luminance && config.luminance.weighting.is_focused() &&
+							changed.elapsed() >= Duration::from_millis(config.adaptive.cooldown)
+						{
+							fade!(history::Cause::Fade).unwrap();
+						}
+					}
+
+					// The window could match different settings now.
+					// Terminals often change title right before closing, so the window
+					// can be gone by now.
+					observer::Event::Rename(_) => {
+						let dirty  = excluded!(head);
+						let result = if mode == interface::Mode::Window {
+							fade!(history::Cause::Fade)
+						}
+						else if mode == interface::Mode::Luminance && (dirty || head.fullscreen || head.detector.is_active()) &&
+							changed.elapsed() >= Duration::from_millis(config.adaptive.cooldown)
+						{
+							fade!(history::Cause::Fade)
+						}
+						else {
+							Ok(())
+						};
+
+						if let Err(err) = result {
+							error!("could not change the backlight: {}", err);
 						}
 					}
 
-					observer::Event::Damage(rect) => {
+					observer::Event::Damage(rects) => {
 						if mode == interface::Mode::Luminance && !screensaver {
-							let refreshed = screen.damage(rect, threshold).unwrap();
+							let threshold = if config.adaptive.refresh > 0 {
+								config.adaptive.threshold
+							}
+							else {
+								u64::max_value()
+							};
+
+							let refreshed = head.screen.damage(&rects, threshold).unwrap();
+							let video     = head.detector.damage(&rects, head.display.width(), head.display.height());
+
+							if video {
+								info!("screen {}: video {}", head.display.screen(), if head.detector.is_active() { "started" } else { "stopped" });
+							}
 
 							if !refreshed {
-								if !rated {
-									timer.refresh(refresh).unwrap();
-									rated = true;
-								}
+								refresh!(config.adaptive.refresh);
 							}
-							else if changed.elapsed().as_secs() >= 1 {
-								fade!(cache.get(cache::Mode::Luminance(screen.luminance())).unwrap()).unwrap()
+							else if changed.elapsed() >= Duration::from_millis(config.adaptive.cooldown) {
+								fade!(history::Cause::Fade).unwrap()
 							}
 						}
 					}
 
 					observer::Event::Resize(width, height) => {
-						screen.resize(width, height).unwrap();
+						head.screen.resize(width, height).unwrap();
+						head.exclude(&config);
+					}
+
+					// The backlights come and go with their outputs.
+					observer::Event::Outputs => {
+						if let Err(err) = head.rediscover(&config) {
+							error!("screen {}: could not rediscover the outputs: {}", head.display.screen(), err);
+						}
+
+						let cause = if rules!() {
+							history::Cause::Profile
+						}
+						else {
+							history::Cause::Fade
+						};
+
+						fade!(cause).unwrap();
 					}
 				}
 			}
 		}
 	}
+
+	// Nobody is listening anymore.
+	interface.close();
 }
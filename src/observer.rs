@@ -16,13 +16,15 @@
 // along with dux.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::thread;
+use std::cmp;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::ops::Deref;
 
 use xcb;
 use channel::{self, Receiver};
 
-use crate::{Display, error};
+use crate::{Display, error, cache};
 
 /// Handles events from a `Display` and sends the appropriate ones.
 pub struct Observer {
@@ -40,24 +42,53 @@ pub enum Event {
 	/// A window's position/size has changed.
 	Change(xcb::Window),
 
-	/// Some screen area changed.
-	Damage(xcb::Rectangle),
+	/// Some screen areas changed.
+	Damage(Vec<xcb::Rectangle>),
 
 	/// The active window changed.
 	Active(Option<xcb::Window>),
 
-	/// The current desktop changed.
-	Desktop(i32),
+	/// The active window changed its title or class.
+	Rename(xcb::Window),
+
+	/// The active window entered or left fullscreen.
+	Fullscreen(bool),
+
+	/// The areas reserved by panels and docks changed.
+	Struts(Vec<xcb::Rectangle>),
+
+	/// The current desktop changed, or was renamed, along with every named
+	/// desktop.
+	Desktop(cache::Desktop, Vec<cache::Desktop>),
 
 	/// The screen has been resized/rotated.
 	Resize(u32, u32),
+
+	/// Monitors have been connected, disconnected or reconfigured.
+	Outputs,
 }
 
 impl Observer {
 	/// Get the current desktop.
-	pub fn desktop(display: &Display) -> error::Result<i32> {
-		xcb::get_property(display, false, display.root(), display.CURRENT_DESKTOP(), xcb::ATOM_CARDINAL, 0, 1)
-			.get_reply()?.value::<i32>().get(0).cloned().ok_or(error::Error::Unsupported)
+	pub fn desktop(display: &Display) -> error::Result<cache::Desktop> {
+		let index = xcb::get_property(display, false, display.root(), display.CURRENT_DESKTOP(), xcb::ATOM_CARDINAL, 0, 1)
+			.get_reply()?.value::<i32>().get(0).cloned().ok_or(error::Error::Unsupported)?;
+
+		let name = Observer::desktops(display).into_iter()
+			.find(|desktop| desktop.index == index).and_then(|desktop| desktop.name);
+
+		Ok(cache::Desktop { index, name })
+	}
+
+	/// Get the desktops the window manager gave a name to.
+	pub fn desktops(display: &Display) -> Vec<cache::Desktop> {
+		// Not every window manager names its desktops.
+		xcbu::ewmh::get_desktop_names(display, display.screen()).get_reply().ok()
+			.map(|reply| reply.strings().iter().enumerate()
+				.filter(|&(_, name)| !name.is_empty())
+				.map(|(index, name)| cache::Desktop { index: index as i32, name: Some(name.to_string()) })
+				.collect())
+			.unwrap_or_default()
 	}
 
 	/// Get the currently active window, if any.
@@ -73,6 +104,200 @@ impl Observer {
 		}
 	}
 
+	/// Check if the given window is fullscreen.
+	pub fn is_fullscreen(display: &Display, window: xcb::Window) -> bool {
+		xcb::get_property(display, false, window, display.WM_STATE(), xcb::ATOM_ATOM, 0, 32).get_reply()
+			.map(|r| r.value::<xcb::Atom>().contains(&display.WM_STATE_FULLSCREEN()))
+			.unwrap_or(false)
+	}
+
+	/// Listen for property changes on the given window instead of the previous
+	/// one.
+	fn watch(display: &Display, struts: &HashMap<xcb::Window, Vec<u32>>, previous: Option<xcb::Window>, current: Option<xcb::Window>) {
+		// The previous window could be gone already, so errors are ignored.
+		if let Some(id) = previous {
+			xcb::change_window_attributes(display, id, &[(xcb::CW_EVENT_MASK, Observer::mask(struts, id, false))]);
+		}
+
+		if let Some(id) = current {
+			xcb::change_window_attributes(display, id, &[(xcb::CW_EVENT_MASK, Observer::mask(struts, id, true))]);
+		}
+
+		display.flush();
+	}
+
+	/// Get the geometry of the given window relative to the root window.
+	pub fn geometry(display: &Display, window: xcb::Window) -> error::Result<xcb::Rectangle> {
+		let geometry = xcb::get_geometry(display, window).get_reply()?;
+		let position = xcb::translate_coordinates(display, window, display.root(), 0, 0).get_reply()?;
+
+		Ok(xcb::Rectangle::new(position.dst_x(), position.dst_y(), geometry.width(), geometry.height()))
+	}
+
+	/// Get the windows managed by the window manager.
+	pub fn clients(display: &Display) -> error::Result<Vec<xcb::Window>> {
+		Ok(xcb::get_property(display, false, display.root(), display.CLIENT_LIST(), xcb::ATOM_WINDOW, 0, u32::max_value())
+			.get_reply()?.value::<xcb::Window>().to_vec())
+	}
+
+	/// Check if the given window is being shown.
+	pub fn is_viewable(display: &Display, window: xcb::Window) -> bool {
+		xcb::get_window_attributes(display, window).get_reply()
+			.map(|a| a.map_state() == xcb::MAP_STATE_VIEWABLE as u8)
+			.unwrap_or(false)
+	}
+
+	/// Get the strut reserved by the given window, if any.
+	///
+	/// Windows whose properties can't be fetched, because they're gone for
+	/// instance, have no strut.
+	fn strut(display: &Display, window: xcb::Window) -> Option<Vec<u32>> {
+		let partial = xcb::get_property(display, false, window, display.WM_STRUT_PARTIAL(), xcb::ATOM_CARDINAL, 0, 12)
+			.get_reply().ok()?.value::<u32>().to_vec();
+
+		if partial.len() == 12 {
+			return Some(partial);
+		}
+
+		let mut strut = xcb::get_property(display, false, window, display.WM_STRUT(), xcb::ATOM_CARDINAL, 0, 4)
+			.get_reply().ok()?.value::<u32>().to_vec();
+
+		if strut.len() != 4 {
+			return None;
+		}
+
+		// The plain strut has no ranges, so it spans the whole edge.
+		strut.resize(12, 0);
+		Some(strut)
+	}
+
+	/// Get the screen areas reserved by the given struts.
+	fn reserved<'a, I: IntoIterator<Item = &'a Vec<u32>>>(struts: I, width: u32, height: u32) -> Vec<xcb::Rectangle> {
+		let (width, height) = (width as i32, height as i32);
+		let mut result      = Vec::new();
+
+		// An empty range is what most panels set when they span the whole edge.
+		fn range(start: i32, end: i32, size: i32) -> (i32, i32) {
+			if start == 0 && end == 0 {
+				(0, size)
+			}
+			else {
+				(start, end - start + 1)
+			}
+		}
+
+		for strut in struts {
+			let s = strut.iter().map(|&v| v as i32).collect::<Vec<_>>();
+			let (left, right)  = (range(s[4], s[5], height), range(s[6], s[7], height));
+			let (top, bottom)  = (range(s[8], s[9], width), range(s[10], s[11], width));
+			let edges = [
+				(s[0], (0, left.0), (s[0], left.1)),
+				(s[1], (width - s[1], right.0), (s[1], right.1)),
+				(s[2], (top.0, 0), (top.1, s[2])),
+				(s[3], (bottom.0, height - s[3]), (bottom.1, s[3])),
+			];
+
+			for &(size, (x, y), (w, h)) in &edges {
+				if size > 0 && w > 0 && h > 0 {
+					result.push(xcb::Rectangle::new(x as i16, y as i16, w as u16, h as u16));
+				}
+			}
+		}
+
+		result
+	}
+
+	/// Get the events to listen for on the given window.
+	///
+	/// Windows with struts are followed until they're gone, the active window
+	/// only while it is active.
+	fn mask(struts: &HashMap<xcb::Window, Vec<u32>>, window: xcb::Window, active: bool) -> u32 {
+		if struts.contains_key(&window) {
+			xcb::EVENT_MASK_PROPERTY_CHANGE | xcb::EVENT_MASK_STRUCTURE_NOTIFY
+		}
+		else if active {
+			xcb::EVENT_MASK_PROPERTY_CHANGE
+		}
+		else {
+			xcb::EVENT_MASK_NO_EVENT
+		}
+	}
+
+	/// Get the geometry of the shown windows whose WM_CLASS instance or class
+	/// name is any of the given ones.
+	pub fn matching(display: &Display, classes: &[String]) -> error::Result<Vec<xcb::Rectangle>> {
+		let mut result = Vec::new();
+
+		for window in Observer::clients(display)? {
+			let name = if let Ok(name) = xcbu::icccm::get_wm_class(display, window).get_reply() {
+				name
+			}
+			else {
+				continue;
+			};
+
+			if !classes.iter().any(|c| c.eq_ignore_ascii_case(name.instance()) || c.eq_ignore_ascii_case(name.class())) {
+				continue;
+			}
+
+			// The window could be gone by now, which shouldn't drop the others.
+			if Observer::is_viewable(display, window) {
+				if let Ok(geometry) = Observer::geometry(display, window) {
+					result.push(geometry);
+				}
+			}
+		}
+
+		Ok(result)
+	}
+
+	/// Merge the rectangles whose bounding box doesn't cover much more than
+	/// they do, to reduce the number of fetches.
+	fn coalesce(mut rects: Vec<xcb::Rectangle>) -> Vec<xcb::Rectangle> {
+		fn area(rect: &xcb::Rectangle) -> u32 {
+			u32::from(rect.width()) * u32::from(rect.height())
+		}
+
+		fn bounds(a: &xcb::Rectangle, b: &xcb::Rectangle) -> xcb::Rectangle {
+			let x = cmp::min(a.x(), b.x());
+			let y = cmp::min(a.y(), b.y());
+			let w = cmp::max(i32::from(a.x()) + i32::from(a.width()), i32::from(b.x()) + i32::from(b.width())) - i32::from(x);
+			let h = cmp::max(i32::from(a.y()) + i32::from(a.height()), i32::from(b.y()) + i32::from(b.height())) - i32::from(y);
+
+			xcb::Rectangle::new(x, y, w as u16, h as u16)
+		}
+
+		// The slack makes rectangles close to each other merge, since the screen
+		// is fetched in tiles anyway.
+		const SLACK: u32 = 4 * 16 * 16;
+
+		let mut merged = true;
+
+		while merged {
+			merged = false;
+
+			let mut result: Vec<xcb::Rectangle> = Vec::with_capacity(rects.len());
+
+			for rect in rects {
+				let candidate = result.iter_mut().find(|other| {
+					area(&bounds(other, &rect)) <= (area(other) + area(&rect)) * 5 / 4 + SLACK
+				});
+
+				if let Some(other) = candidate {
+					*other = bounds(other, &rect);
+					merged = true;
+				}
+				else {
+					result.push(rect);
+				}
+			}
+
+			rects = result;
+		}
+
+		rects
+	}
+
 	/// Spawn the observer on the given `Display`.
 	pub fn spawn(display: Arc<Display>) -> error::Result<Self> {
 		let (sender, receiver) = channel::bounded(1);
@@ -83,71 +308,233 @@ impl Observer {
 				xcb::EVENT_MASK_SUBSTRUCTURE_NOTIFY |
 				xcb::EVENT_MASK_PROPERTY_CHANGE)]).request_check()?;
 
-		// Listen for damage areas, if the other report levels worked it would be
-		// nice, but alas, we're gonna get spammed by damages.
-		let damage = display.generate_id();
-		xcb::damage::create_checked(&display, damage, display.root(), xcb::damage::REPORT_LEVEL_RAW_RECTANGLES as u8)
-			.request_check()?;
+		// Listen for damage areas, only one event is sent when the damage becomes
+		// non-empty, then the whole damage is moved into a region and fetched at
+		// once, so while the daemon is busy changes keep accumulating on the
+		// server instead of being queued one by one.
+		//
+		// Without DAMAGE no events are sent and the screen has to be polled.
+		let damage = if display.capabilities().damage {
+			let id = display.generate_id();
+			xcb::damage::create_checked(&display, id, display.root(), xcb::damage::REPORT_LEVEL_NON_EMPTY as u8)
+				.request_check()?;
+
+			let region = display.generate_id();
+			xcb::xfixes::create_region_checked(&display, region, &[]).request_check()?;
+
+			Some((id, region))
+		}
+		else {
+			None
+		};
 
 		thread::spawn(move || {
 			// Send the currently active desktop if present.
-			if let Ok(id) = Observer::desktop(&display) {
-				sender.send(Event::Desktop(id)).unwrap();
+			if let Ok(desktop) = Observer::desktop(&display) {
+				sender.send(Event::Desktop(desktop, Observer::desktops(&display))).unwrap();
+			}
+
+			// The active window is watched for state changes.
+			let mut watched = None;
+
+			// The struts are tracked as their windows come and go, instead of
+			// walking the whole tree every time.
+			let mut struts  = HashMap::new();
+			let mut clients = Vec::new();
+			let mut size    = (display.width(), display.height());
+
+			macro_rules! reserved {
+				() => (
+					sender.send(Event::Struts(Observer::reserved(struts.values(), size.0, size.1))).unwrap()
+				);
+			}
+
+			// Check the strut of the given window, returns whether it changed.
+			macro_rules! strut {
+				($id:expr) => ({
+					let id    = $id;
+					let value = Observer::strut(&display, id).filter(|_| Observer::is_viewable(&display, id));
+
+					match value {
+						Some(value) => if struts.get(&id) != Some(&value) {
+							let known = struts.insert(id, value).is_some();
+
+							if !known {
+								xcb::change_window_attributes(&display, id, &[(xcb::CW_EVENT_MASK, Observer::mask(&struts, id, watched == Some(id)))]);
+								display.flush();
+							}
+
+							true
+						}
+						else {
+							false
+						},
+
+						None =>
+							struts.remove(&id).is_some()
+					}
+				})
+			}
+
+			// Docks aren't always top level windows, nor are they always managed.
+			macro_rules! clients {
+				() => ({
+					let current = Observer::clients(&display).unwrap_or_default();
+					let mut changed = false;
+
+					for &id in current.iter().filter(|id| !clients.contains(*id)) {
+						changed |= strut!(id);
+					}
+
+					clients = current;
+					changed
+				})
+			}
+
+			if let Ok(tree) = xcb::query_tree(&display, display.root()).get_reply() {
+				for &id in tree.children() {
+					strut!(id);
+				}
+			}
+
+			clients!();
+			reserved!();
+
+			macro_rules! active {
+				($id:expr) => ({
+					let id = $id;
+
+					Observer::watch(&display, &struts, watched, id);
+					watched = id;
+
+					sender.send(Event::Active(id)).unwrap();
+					sender.send(Event::Fullscreen(id.map(|id| Observer::is_fullscreen(&display, id)).unwrap_or(false))).unwrap();
+				})
 			}
 
 			// Send the currently active window if present.
 			if let Ok(id) = Observer::window(&display) {
-				sender.send(Event::Active(id)).unwrap();
+				active!(id);
 			}
 
 			while let Some(event) = display.wait_for_event() {
 				match event.response_type() {
+					// Windows with struts also report their own changes, so only the ones
+					// reported on the root window are sent.
 					xcb::MAP_NOTIFY => {
 						let event = unsafe { xcb::cast_event::<xcb::MapNotifyEvent>(&event) };
 
-						sender.send(Event::Show(event.window())).unwrap();
+						if strut!(event.window()) {
+							reserved!();
+						}
+
+						if event.event() == display.root() {
+							sender.send(Event::Show(event.window())).unwrap();
+						}
 					}
 
 					xcb::UNMAP_NOTIFY => {
 						let event = unsafe { xcb::cast_event::<xcb::UnmapNotifyEvent>(&event) };
 
-						sender.send(Event::Hide(event.window())).unwrap();
+						if struts.remove(&event.window()).is_some() {
+							reserved!();
+						}
+
+						if event.event() == display.root() {
+							sender.send(Event::Hide(event.window())).unwrap();
+						}
+					}
+
+					xcb::DESTROY_NOTIFY => {
+						let event = unsafe { xcb::cast_event::<xcb::DestroyNotifyEvent>(&event) };
+
+						if struts.remove(&event.window()).is_some() {
+							reserved!();
+						}
 					}
 
 					xcb::CONFIGURE_NOTIFY => {
 						let event = unsafe { xcb::cast_event::<xcb::ConfigureNotifyEvent>(&event) };
 
-						sender.send(Event::Change(event.window())).unwrap();
+						if event.event() == display.root() {
+							sender.send(Event::Change(event.window())).unwrap();
+						}
 					}
 
 					xcb::PROPERTY_NOTIFY => {
 						let event = unsafe { xcb::cast_event::<xcb::PropertyNotifyEvent>(&event) };
 
 						match event.atom() {
-							prop if prop == display.CURRENT_DESKTOP() && event.state() == xcb::PROPERTY_NEW_VALUE as u8 => {
-								if let Ok(id) = Observer::desktop(&display) {
-									sender.send(Event::Desktop(id)).unwrap();
+							prop if event.window() == display.root() && (prop == display.CURRENT_DESKTOP() || prop == display.DESKTOP_NAMES()) &&
+								event.state() == xcb::PROPERTY_NEW_VALUE as u8 =>
+							{
+								if let Ok(desktop) = Observer::desktop(&display) {
+									sender.send(Event::Desktop(desktop, Observer::desktops(&display))).unwrap();
 								}
 							}
 
-							prop if prop == display.ACTIVE_WINDOW() && event.state() == xcb::PROPERTY_NEW_VALUE as u8 => {
+							prop if event.window() == display.root() && prop == display.ACTIVE_WINDOW() && event.state() == xcb::PROPERTY_NEW_VALUE as u8 => {
 								if let Ok(id) = Observer::window(&display) {
-									sender.send(Event::Active(id)).unwrap();
+									if id != watched {
+										active!(id);
+									}
+								}
+							}
+
+							prop if event.window() == display.root() && prop == display.CLIENT_LIST() => {
+								if clients!() {
+									reserved!();
+								}
+							}
+
+							prop if prop == display.WM_STRUT() || prop == display.WM_STRUT_PARTIAL() => {
+								if strut!(event.window()) {
+									reserved!();
 								}
 							}
 
+							prop if Some(event.window()) == watched && prop == display.WM_STATE() => {
+								sender.send(Event::Fullscreen(Observer::is_fullscreen(&display, event.window()))).unwrap();
+							}
+
+							// Browsers and terminals change their title, and sometimes their
+							// class, without changing the active window.
+							prop if Some(event.window()) == watched &&
+								(prop == xcb::ATOM_WM_NAME || prop == xcb::ATOM_WM_CLASS || prop == display.WM_NAME()) =>
+							{
+								sender.send(Event::Rename(event.window())).unwrap();
+							}
+
 							_ => ()
 						}
 					}
 
-					// Handle damaged rectangles.
-					e if e == display.damage().first_event() => {
-						let event = unsafe { xcb::cast_event::<xcb::damage::NotifyEvent>(&event) };
-						sender.send(Event::Damage(event.area())).unwrap();
+					// Handle damaged areas.
+					e if damage.is_some() && e == display.damage().first_event() + xcb::damage::NOTIFY => {
+						if let Some((damage, region)) = damage {
+							// Move the damage into the region, which marks it as handled.
+							xcb::damage::subtract(&display, damage, xcb::xfixes::REGION_NONE, region);
+
+							if let Ok(reply) = xcb::xfixes::fetch_region(&display, region).get_reply() {
+								let rects = Observer::coalesce(reply.rectangles().collect());
+
+								if !rects.is_empty() {
+									sender.send(Event::Damage(rects)).unwrap();
+								}
+							}
+						}
+					}
+
+					// Handle monitor changes.
+					e if e == display.randr().first_event() + xcb::randr::NOTIFY => {
+						let event = unsafe { xcb::cast_event::<xcb::randr::NotifyEvent>(&event) };
+
+						match event.sub_code() as u32 {
+							xcb::randr::NOTIFY_OUTPUT_CHANGE | xcb::randr::NOTIFY_CRTC_CHANGE =>
+								sender.send(Event::Outputs).unwrap(),
 
-						// Mark the damage region as handled.
-						xcb::damage::subtract(&display, damage, xcb::xfixes::REGION_NONE, xcb::xfixes::REGION_NONE);
-						display.flush();
+							_ => ()
+						}
 					}
 
 					// Handle screen changes.
@@ -155,7 +542,12 @@ impl Observer {
 						let event = unsafe { xcb::cast_event::<xcb::randr::ScreenChangeNotifyEvent>(&event) };
 
 						if event.root() == display.root() {
-							sender.send(Event::Resize(u32::from(event.width()), u32::from(event.height()))).unwrap();
+							size = (u32::from(event.width()), u32::from(event.height()));
+							sender.send(Event::Resize(size.0, size.1)).unwrap();
+
+							if !struts.is_empty() {
+								reserved!();
+							}
 						}
 					}
 
@@ -175,3 +567,34 @@ impl Deref for Observer {
 		&self.receiver
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rects(struts: &[Vec<u32>]) -> Vec<(i16, i16, u16, u16)> {
+		Observer::reserved(struts, 1920, 1080).iter()
+			.map(|r| (r.x(), r.y(), r.width(), r.height()))
+			.collect()
+	}
+
+	#[test]
+	fn reserved() {
+		// A panel spanning part of the bottom edge.
+		assert_eq!(rects(&[vec![0, 0, 0, 30, 0, 0, 0, 0, 0, 0, 100, 199]]),
+			vec![(100, 1050, 100, 30)]);
+
+		// A plain strut, and a partial one with empty ranges, span the whole edge.
+		assert_eq!(rects(&[vec![50, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]]),
+			vec![(0, 0, 50, 1080)]);
+		assert_eq!(rects(&[vec![0, 0, 24, 0, 0, 0, 0, 0, 0, 0, 0, 0], vec![0, 40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]]),
+			vec![(0, 0, 1920, 24), (1880, 0, 40, 1080)]);
+
+		// A range starting at zero is still a range.
+		assert_eq!(rects(&[vec![0, 0, 24, 0, 0, 0, 0, 0, 0, 959, 0, 0]]),
+			vec![(0, 0, 960, 24)]);
+
+		// Nothing reserved.
+		assert!(rects(&[vec![0; 12]]).is_empty());
+	}
+}
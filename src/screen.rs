@@ -17,111 +17,162 @@
 
 use std::sync::Arc;
 use std::time::Instant;
-use std::cmp;
+use std::ops::{Deref, DerefMut};
 
 use xcb;
 use xcbu;
 
 use crate::{Display, error};
+use crate::luminance::{Engine, Format, Model};
 
-/// Manages luminances and screen content through the MIT-SHM extension.
+/// Manages luminances and screen content through the MIT-SHM extension, or
+/// plain `GetImage` requests when it's not available.
+///
+/// The luminance itself is computed by the `Engine` it dereferences to.
 pub struct Screen {
 	display: Arc<Display>,
-	image:   xcbu::image::shm::Image,
-
-	width:  u32,
-	height: u32,
+	capture: Capture,
+	engine:  Engine,
+	rated:   Option<Instant>,
+}
 
-	luminances: Vec<f32>,
-	luminance:  u64,
+/// How the screen contents are fetched.
+enum Capture {
+	/// Through an image in shared memory.
+	Shm(xcbu::image::shm::Image),
 
-	cache: Vec<(u32, u32, u32, u32)>,
-	rated: Option<Instant>,
+	/// Through a new image for every request, slower but works on remote
+	/// connections too.
+	Plain,
 }
 
-const PRECISION: f32 = 1_000_000.0;
+impl Capture {
+	/// Create an image in shared memory as big as the display, if possible.
+	fn open(display: &Display, format: &Format, width: u32, height: u32) -> Capture {
+		if display.capabilities().shm {
+			if let Ok(image) = xcbu::image::shm::create(display, format.depth(), width as u16, height as u16) {
+				return Capture::Shm(image);
+			}
+		}
+
+		Capture::Plain
+	}
+}
 
 impl Screen {
 	/// Create a new screen holder.
 	pub fn open(display: Arc<Display>, width: u32, height: u32) -> error::Result<Screen> {
-		// Create an image in shared memory as big as the display.
-		let image = xcbu::image::shm::create(&display, 24, width as u16, height as u16)?;
+		let format  = Format::new(&display.visual()?).ok_or(error::Error::Unsupported)?;
+		let capture = Capture::open(&display, &format, width, height);
+
+		let mut screen = Screen {
+			display, capture,
+			engine: Engine::new(format, width, height),
+			rated:  None,
+		};
+
+		// Find out the outputs the screen is split into.
+		screen.layout()?;
+
+		Ok(screen)
+	}
+
+	/// Update the output regions from the current screen configuration.
+	pub fn layout(&mut self) -> error::Result<()> {
+		self.engine.layout(self.display.outputs()?);
+
+		Ok(())
+	}
 
-		// Set up the luminances vector, again as big as the display.
-		let luminances = vec![0.0; (width * height) as usize];
+	/// Change the luminance model, this refreshes the whole screen.
+	pub fn model(&mut self, value: Model) -> error::Result<()> {
+		if self.engine.model(value) {
+			self.poll()?;
+		}
 
-		Ok(Screen {
-			display, image,
-			width, height,
+		Ok(())
+	}
 
-			luminances, luminance:  0,
+	/// Change the sampling stride, this refreshes the whole screen.
+	pub fn stride(&mut self, value: u32) -> error::Result<()> {
+		if self.engine.stride(value) {
+			self.poll()?;
+		}
 
-			cache: Vec::new(),
-			rated: None,
-		})
+		Ok(())
 	}
 
 	/// Resize the screen.
 	pub fn resize(&mut self, width: u32, height: u32) -> error::Result<()> {
 		// Create a new image only if the new size is bigger than the actual size.
-		if self.image.actual_width() * self.image.actual_height() < (width * height) as u16 {
-			self.image = xcbu::image::shm::create(&self.display, 24, width as u16, height as u16)?;
-		}
-		else {
-			self.image.resize(width as u16, height as u16);
+		if let Capture::Shm(ref mut image) = self.capture {
+			if u32::from(image.actual_width()) < width || u32::from(image.actual_height()) < height {
+				self.capture = Capture::open(&self.display, self.engine.format(), width, height);
+			}
+			else {
+				image.resize(width as u16, height as u16);
+			}
 		}
 
-		self.width  = width;
-		self.height = height;
-
-		// Reset the luminance values.
-		self.luminances.resize((width * height) as usize, 0.0);
-		self.luminance = 0;
+		// Reset the tiles.
+		self.engine.resize(width, height);
 
-		// This gets optimized to a memset.
-		for item in &mut self.luminances {
-			*item = 0.0;
-		}
+		// The outputs have most likely changed too.
+		self.layout()?;
 
 		// Update the whole screen.
-		self.refresh(0, 0, width, height)
+		self.poll()
+	}
+
+	/// Create the shared memory image again and refresh the whole screen, the
+	/// old one might be gone after a suspend.
+	pub fn recapture(&mut self) -> error::Result<()> {
+		self.capture = Capture::open(&self.display, self.engine.format(), self.engine.width(), self.engine.height());
+		self.poll()
 	}
 
 	/// Flush any cached damages.
 	pub fn flush(&mut self) -> error::Result<()> {
-		if self.rated.is_none() || self.cache.is_empty() {
+		if self.rated.is_none() {
 			return Ok(());
 		}
 
-		let (x, y, w, h) = self.cache.drain(..).fold((u32::max_value(), u32::max_value(), 0, 0),
-			|(xmin, ymin, xmax, ymax), (x, y, w, h)| {
-				(cmp::min(xmin, x), cmp::min(ymin, y), cmp::max(xmax, x + w), cmp::max(ymax, y + h))
-			});
-
 		self.rated = None;
-		self.refresh(x, y, w - x, h - y)
+
+		for (x, y, width, height) in self.engine.dirty() {
+			self.refresh(x, y, width, height)?;
+		}
+
+		Ok(())
 	}
 
-	/// Mark a screen area as damaged.
-	pub fn damage(&mut self, rect: xcb::Rectangle, threshold: u64) -> error::Result<bool> {
-		let x = rect.x() as u32;
-		let y = rect.y() as u32;
-		let w = u32::from(rect.width());
-		let h = u32::from(rect.height());
+	/// Mark screen areas as damaged.
+	///
+	/// When they're bigger than `threshold` in total they're only cached if a
+	/// flush is already pending, and `false` is returned so the caller knows a
+	/// flush is needed, otherwise they're refreshed right away.
+	pub fn damage(&mut self, rects: &[xcb::Rectangle], threshold: u64) -> error::Result<bool> {
+		let total = rects.iter().map(|r| u64::from(r.width()) * u64::from(r.height())).sum::<u64>();
+
+		if total >= threshold {
+			for rect in rects {
+				self.engine.mark(rect.x() as u32, rect.y() as u32, u32::from(rect.width()), u32::from(rect.height()));
+			}
 
-		if self.rated.is_some() && u64::from(w * h) >= threshold {
-			self.cache.push((x, y, w, h));
+			if self.rated.is_none() {
+				self.rated = Some(Instant::now());
 
-			Ok(false)
-		}
-		else if u64::from(w * h) >= threshold {
-			self.rated = Some(Instant::now());
-			self.refresh(x, y, w, h)?;
+				for (x, y, width, height) in self.engine.dirty() {
+					self.refresh(x, y, width, height)?;
+				}
+			}
 
 			Ok(false)
 		}
 		else {
-			self.refresh(x, y, w, h)?;
+			for rect in rects {
+				self.refresh(rect.x() as u32, rect.y() as u32, u32::from(rect.width()), u32::from(rect.height()))?;
+			}
 
 			Ok(true)
 		}
@@ -129,58 +180,51 @@ impl Screen {
 
 	/// Get the given screen section and update the luminance values.
 	pub fn refresh(&mut self, x: u32, y: u32, width: u32, height: u32) -> error::Result<()> {
-		// Note that this will resize the image to fit the section, but that
-		// doesn't matter because it will never be bigger than the screen.
-		xcbu::image::shm::area(&self.display, self.display.root(), &mut self.image,
-			x as i16, y as i16, width as u16, height as u16, !0)?;
-
-		// Update the pixel values relative to the section.
-		for xx in x .. width {
-			for yy in y .. height {
-				let rgb = {
-					let data   = self.image.data();
-					let offset = (((xx - x) * 4) + ((yy - y) * width * 4)) as usize;
-
-					(data[offset], data[offset + 1], data[offset + 2])
-				};
-
-				self.put(xx, yy, rgb);
-			}
+		let (x, y, width, height) = if let Some(section) = self.engine.align(x, y, width, height) {
+			section
 		}
+		else {
+			return Ok(());
+		};
 
-		Ok(())
-	}
+		let plain;
+		let image: &xcbu::image::Image = match self.capture {
+			// Note that this will resize the image to fit the section, but that
+			// doesn't matter because it will never be bigger than the screen.
+			Capture::Shm(ref mut image) =>
+				xcbu::image::shm::area(&self.display, self.display.root(), image,
+					x as i16, y as i16, width as u16, height as u16, !0)?,
 
-	/// Puts a pixel at the given coordinates, updating the total luminance.
-	pub fn put(&mut self, x: u32, y: u32, (r, g, b): (u8, u8, u8)) -> f32 {
-		// Extract the RGB channels and normalize them to `0.0` - `1.0`.
-		let r = f32::from(r) / 255.0;
-		let g = f32::from(g) / 255.0;
-		let b = f32::from(b) / 255.0;
+			Capture::Plain => {
+				plain = xcbu::image::get(&self.display, self.display.root(),
+					x as i16, y as i16, width as u16, height as u16, !0, xcb::IMAGE_FORMAT_Z_PIXMAP)?;
 
-		// Calculate the perceived luminance.
-		let l = (r * 0.299) + (g * 0.587) + (b * 0.114);
+				&plain
+			}
+		};
 
-		// The index within the luminance vector based on the position.
-		let i = (x + (y * self.width)) as usize;
+		self.engine.update(image.data(), image.stride() as usize, x, y, width, height);
 
-		// Update the total luminance in place, we use an `u64` to contain the
-		// total to avoid incremental precision errors because of the repeated
-		// operations.
-		//
-		// The actual value is clamped to a constant precision and converted to an
-		// `u64` value.
-		self.luminance -= (self.luminances[i].powi(2) * PRECISION) as u64;
-		self.luminance += (l.powi(2) * PRECISION) as u64;
+		Ok(())
+	}
+
+	/// Refresh the whole screen, used when damages aren't reported.
+	pub fn poll(&mut self) -> error::Result<()> {
+		let (width, height) = (self.engine.width(), self.engine.height());
+		self.refresh(0, 0, width, height)
+	}
+}
 
-		// Save the new luminance so it can be restored when this pixel is changed.
-		self.luminances[i] = l;
+impl Deref for Screen {
+	type Target = Engine;
 
-		l
+	fn deref(&self) -> &Self::Target {
+		&self.engine
 	}
+}
 
-	/// Get the RMS luminance.
-	pub fn luminance(&self) -> f32 {
-		((self.luminance as f32 / PRECISION) / (self.width * self.height) as f32).sqrt()
+impl DerefMut for Screen {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.engine
 	}
 }
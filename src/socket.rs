@@ -0,0 +1,366 @@
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// This file is part of dux.
+//
+// dux is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// dux is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with dux.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use json::{self, object, JsonValue};
+use channel::{self, Sender};
+
+use crate::error;
+use crate::interface::{Event, Mode, Status, PROPERTIES};
+
+/// Get the path to the socket.
+///
+/// Without `XDG_RUNTIME_DIR` it lives in a private directory within the
+/// temporary one, which is refused unless it's owned by us and nobody else can
+/// get into it.
+pub fn path() -> error::Result<PathBuf> {
+	if let Some(dir) = env::var_os("XDG_RUNTIME_DIR") {
+		return Ok(PathBuf::from(dir).join("dux.sock"));
+	}
+
+	let dir = env::temp_dir().join(format!("dux-{}", unsafe { libc::geteuid() }));
+
+	if let Err(err) = fs::DirBuilder::new().mode(0o700).create(&dir) {
+		if err.kind() != io::ErrorKind::AlreadyExists {
+			return Err(err.into());
+		}
+	}
+
+	private(&dir)?;
+
+	Ok(dir.join("dux.sock"))
+}
+
+/// Make sure the given directory belongs to us and nobody else can get into
+/// it.
+fn private(dir: &Path) -> error::Result<()> {
+	let metadata = fs::symlink_metadata(dir)?;
+
+	if !metadata.is_dir() || metadata.uid() != unsafe { libc::geteuid() } || metadata.mode() & 0o077 != 0 {
+		return Err(error::Error::Message(format!("{} is not a private directory", dir.display())));
+	}
+
+	Ok(())
+}
+
+/// Listen on the control socket, the returned sender takes the state changes
+/// to send to the subscribers.
+///
+/// It's an alternative to the session bus speaking JSON lines: every request
+/// is an object with the `method` and its `value`, if it takes any, and gets
+/// back an object with either the `value` or the `error`. After a `Subscribe`
+/// the changed properties are sent as `{ "signal": "PropertiesChanged",
+/// "changed": { ... } }` as they change.
+pub fn serve<T: AsRef<Path>>(path: T, sender: Sender<Event>, status: Arc<Mutex<Status>>) -> error::Result<Sender<(Status, Status)>> {
+	let path = path.as_ref();
+
+	// A socket nobody answers on is left over from a daemon that didn't stop
+	// cleanly.
+	if path.exists() {
+		if UnixStream::connect(path).is_ok() {
+			return Err(error::Error::Message(format!("{} is already in use", path.display())));
+		}
+
+		fs::remove_file(path)?;
+	}
+
+	let listener    = UnixListener::bind(path)?;
+	fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+	let subscribers = Arc::new(Mutex::new(Vec::<Arc<Mutex<UnixStream>>>::new()));
+	let (updates, changes) = channel::unbounded::<(Status, Status)>();
+
+	{
+		let subscribers = subscribers.clone();
+
+		thread::spawn(move || {
+			for stream in listener.incoming().filter_map(Result::ok) {
+				let sender      = sender.clone();
+				let status      = status.clone();
+				let subscribers = subscribers.clone();
+
+				thread::spawn(move || {
+					connection(stream, &sender, &status, &subscribers).ok();
+				});
+			}
+		});
+	}
+
+	thread::spawn(move || {
+		while let Ok((old, new)) = changes.recv() {
+			let changed = new.changed(&old);
+
+			if changed.is_empty() {
+				continue;
+			}
+
+			let line = line(&object!{
+				"signal"  => "PropertiesChanged",
+				"changed" => new.json(&changed),
+			});
+
+			// The list isn't held while writing, since a new subscriber holds its
+			// stream while being added.
+			let streams = subscribers.lock().unwrap().clone();
+			let failed  = streams.into_iter()
+				.filter(|stream| stream.lock().unwrap().write_all(&line).is_err())
+				.collect::<Vec<_>>();
+
+			// Subscribers that went away or can't keep up are dropped.
+			if !failed.is_empty() {
+				subscribers.lock().unwrap().retain(|stream| !failed.iter().any(|f| Arc::ptr_eq(f, stream)));
+			}
+		}
+	});
+
+	Ok(updates)
+}
+
+/// Turn the value into a line to send in one go.
+fn line(value: &JsonValue) -> Vec<u8> {
+	let mut line = value.dump().into_bytes();
+	line.push(b'\n');
+
+	line
+}
+
+/// Handle the requests coming from a client.
+///
+/// The replies and the signals share the stream, so it's locked around every
+/// line.
+fn connection(stream: UnixStream, sender: &Sender<Event>, status: &Mutex<Status>, subscribers: &Mutex<Vec<Arc<Mutex<UnixStream>>>>) -> error::Result<()> {
+	let writer = Arc::new(Mutex::new(stream.try_clone()?));
+
+	for line in BufReader::new(stream).lines() {
+		let request   = json::parse(&line?);
+		let subscribe = request.as_ref().map(|r| r["method"] == "Subscribe").unwrap_or(false);
+		let reply     = match request {
+			Ok(_) if subscribe =>
+				Ok(JsonValue::Null),
+
+			Ok(ref request) =>
+				handle(request, sender, status),
+
+			Err(ref err) =>
+				Err(err.to_string()),
+		};
+
+		let reply = match reply {
+			Ok(value) => object!{ "value" => value },
+			Err(err)  => object!{ "error" => err },
+		};
+
+		let mut stream = writer.lock().unwrap();
+
+		// The stream is held until the reply is written, so no change is missed
+		// and the reply still comes before any signal.
+		if subscribe {
+			stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+			subscribers.lock().unwrap().push(writer.clone());
+		}
+
+		stream.write_all(&self::line(&reply))?;
+	}
+
+	Ok(())
+}
+
+/// Handle a request, same as the DBus methods.
+fn handle(request: &JsonValue, sender: &Sender<Event>, status: &Mutex<Status>) -> Result<JsonValue, String> {
+	let value = &request["value"];
+	let event = match request["method"].as_str().unwrap_or("") {
+		"Mode" =>
+			Event::Mode(value.as_str().and_then(Mode::parse).ok_or("invalid mode")?),
+
+		"Profile" =>
+			Event::Profile(value.as_str().ok_or("invalid profile")?.into()),
+
+		"Brightness" =>
+			Event::Brightness(value.as_f32().ok_or("invalid brightness")?),
+
+		"Save" =>
+			Event::Save,
+
+		"Reload" =>
+			Event::Reload,
+
+		"Stop" =>
+			Event::Stop,
+
+		"GetTarget" =>
+			return Ok(status.lock().unwrap().target.into()),
+
+		"ListProfiles" =>
+			return Ok(status.lock().unwrap().profiles.clone().into()),
+
+		"Get" => {
+			let name = value.as_str().filter(|name| PROPERTIES.contains(name)).ok_or("unknown property")?;
+			return Ok(status.lock().unwrap().json(&[name])[name].clone());
+		}
+
+		"GetAll" =>
+			return Ok(status.lock().unwrap().json(PROPERTIES)),
+
+		method =>
+			return Err(format!("unknown method `{}`", method)),
+	};
+
+	sender.send(event).map_err(|_| "the daemon is stopping")?;
+
+	Ok(JsonValue::Null)
+}
+
+/// A connection to the control socket.
+pub struct Client {
+	reader: BufReader<UnixStream>,
+	writer: UnixStream,
+}
+
+impl Client {
+	/// Connect to the given socket.
+	pub fn open<T: AsRef<Path>>(path: T) -> error::Result<Self> {
+		let stream = UnixStream::connect(path)?;
+
+		Ok(Client {
+			reader: BufReader::new(stream.try_clone()?),
+			writer: stream,
+		})
+	}
+
+	/// Call a method and wait for its reply.
+	pub fn request(&mut self, method: &str, value: Option<JsonValue>) -> error::Result<JsonValue> {
+		let mut request = object!{ "method" => method };
+
+		if let Some(value) = value {
+			request["value"] = value;
+		}
+
+		self.writer.write_all(&line(&request))?;
+
+		loop {
+			let mut reply = self.receive()?;
+
+			// Signals can come in before the reply once subscribed.
+			if reply.has_key("signal") {
+				continue;
+			}
+
+			if let Some(err) = reply["error"].as_str() {
+				return Err(error::Error::Message(err.into()));
+			}
+
+			return Ok(reply["value"].take());
+		}
+	}
+
+	/// Get all the properties and the profiles.
+	pub fn status(&mut self) -> error::Result<Status> {
+		let mut status = Status::default();
+		status.apply(&self.request("GetAll", None)?);
+		status.profiles = self.request("ListProfiles", None)?.members()
+			.filter_map(|p| p.as_str().map(String::from)).collect();
+
+		Ok(status)
+	}
+
+	/// Receive the next line, either a reply or a signal.
+	pub fn receive(&mut self) -> error::Result<JsonValue> {
+		let mut line = String::new();
+
+		if self.reader.read_line(&mut line)? == 0 {
+			return Err(error::Error::Message("the daemon closed the connection".into()));
+		}
+
+		Ok(json::parse(&line)?)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::process;
+
+	#[test]
+	fn protocol() {
+		let path = env::temp_dir().join(format!("dux-control-{}.sock", process::id()));
+		fs::remove_file(&path).ok();
+
+		let (sender, receiver) = channel::bounded(1);
+		let status  = Arc::new(Mutex::new(Status { profile: "default".into(), profiles: vec!["default".into(), "night".into()], .. Status::default() }));
+		let updates = serve(&path, sender.clone(), status.clone()).unwrap();
+		assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+
+		// Only one daemon can listen.
+		assert!(serve(&path, sender, status.clone()).is_err());
+
+		let mut client = Client::open(&path).unwrap();
+		assert_eq!(client.request("GetAll", None).unwrap()["Profile"], "default");
+		assert_eq!(client.request("Get", Some("Mode".into())).unwrap(), "luminance");
+		assert_eq!(client.request("ListProfiles", None).unwrap(), json::array!["default", "night"]);
+		assert!(client.request("Mode", Some("nope".into())).is_err());
+		assert!(client.request("Nope", None).is_err());
+
+		client.request("Brightness", Some(42.0.into())).unwrap();
+		match receiver.recv().unwrap() {
+			Event::Brightness(value) => assert_eq!(value, 42.0),
+			event => panic!("unexpected {:?}", event),
+		}
+
+		// The changes are only sent to the subscribers.
+		let mut subscriber = Client::open(&path).unwrap();
+		subscriber.request("Subscribe", None).unwrap();
+
+		let old = status.lock().unwrap().clone();
+		let new = Status { brightness: 60.0, reason: "user: manual".into(), .. old.clone() };
+		updates.send((old, new)).unwrap();
+
+		let signal = subscriber.receive().unwrap();
+		assert_eq!(signal["signal"], "PropertiesChanged");
+		assert_eq!(signal["changed"], object!{ "Brightness" => 60.0, "Reason" => "user: manual" });
+
+		fs::remove_file(&path).ok();
+	}
+
+	#[test]
+	fn private() {
+		let dir  = env::temp_dir().join(format!("dux-private-{}", process::id()));
+		let link = env::temp_dir().join(format!("dux-private-{}-link", process::id()));
+		fs::DirBuilder::new().mode(0o755).create(&dir).unwrap();
+		fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+		std::os::unix::fs::symlink(&dir, &link).unwrap();
+
+		assert!(super::private(&dir).is_err());
+
+		fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+		assert!(super::private(&dir).is_ok());
+
+		// Links could point anywhere.
+		assert!(super::private(&link).is_err());
+
+		fs::remove_file(&link).unwrap();
+		fs::remove_dir(&dir).unwrap();
+	}
+}
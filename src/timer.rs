@@ -26,6 +26,7 @@ use channel::{self, Receiver, Sender, SendError};
 pub struct Timer {
 	receiver: Receiver<Event>,
 	refresh:  Sender<u64>,
+	settings: Sender<Settings>,
 }
 
 #[derive(Debug)]
@@ -48,9 +49,10 @@ pub struct Settings {
 
 impl Timer {
 	/// Spawn the `Timer` thread.
-	pub fn spawn(settings: Settings) -> error::Result<Self> {
+	pub fn spawn(mut current: Settings) -> error::Result<Self> {
 		let (sender, receiver)   = channel::unbounded();
 		let (refresh, refresher) = channel::unbounded();
+		let (settings, updater)  = channel::unbounded();
 
 		// Spawn the refresh timer.
 		{
@@ -72,19 +74,29 @@ impl Timer {
 			loop {
 				thread::sleep(Duration::from_secs(1));
 
-				if save.elapsed().as_secs() >= settings.save {
+				// Pick up any settings change.
+				while let Ok(value) = updater.try_recv() {
+					current = value;
+				}
+
+				if save.elapsed().as_secs() >= current.save {
 					save = Instant::now();
 					sender.send(Event::Save).unwrap();
 				}
 
-				if beat.elapsed().as_secs() >= settings.heartbeat {
+				if beat.elapsed().as_secs() >= current.heartbeat {
 					beat = Instant::now();
 					sender.send(Event::Heartbeat).unwrap();
 				}
 			}
 		});
 
-		Ok(Timer { receiver, refresh })
+		Ok(Timer { receiver, refresh, settings })
+	}
+
+	/// Change the constant timers settings.
+	pub fn update(&self, value: Settings) -> Result<(), SendError<Settings>> {
+		self.settings.send(value)
 	}
 
 	/// Request a refresh after the given milliseconds.
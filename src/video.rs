@@ -0,0 +1,243 @@
+// Copyleft (ↄ) meh. <meh@schizofreni.co> | http://meh.schizofreni.co
+//
+// This file is part of dux.
+//
+// dux is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// dux is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with dux.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Detects video playback from the screen damages.
+///
+/// Video shows up as the same big area being damaged many times a second for
+/// a while, so the biggest damaged rectangle is tracked and once it's been
+/// damaged at a high enough rate for long enough it's considered video.
+pub struct Detector {
+	settings: Settings,
+	area:     Option<xcb::Rectangle>,
+	since:    Instant,
+	last:     Instant,
+	count:    u32,
+	active:   bool,
+}
+
+/// The detection tunables.
+#[derive(Copy, Clone, Debug)]
+pub struct Settings {
+	/// Minimum number of damages per second.
+	pub rate: f32,
+
+	/// Time in milliseconds the rate has to be sustained for.
+	pub time: u64,
+
+	/// Minimum size of the damaged area, as a fraction of the screen.
+	pub area: f32,
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Settings {
+			rate: 15.0,
+			time: 2_000,
+			area: 0.1,
+		}
+	}
+}
+
+impl Detector {
+	pub fn new(settings: Settings) -> Self {
+		let now = Instant::now();
+
+		Detector {
+			settings,
+			area:   None,
+			since:  now,
+			last:   now,
+			count:  0,
+			active: false,
+		}
+	}
+
+	/// Change the settings, any detection starts over.
+	pub fn update(&mut self, settings: Settings) {
+		*self = Detector::new(settings);
+	}
+
+	/// Whether video is playing.
+	pub fn is_active(&self) -> bool {
+		self.active
+	}
+
+	/// Feed the damaged rectangles, returns whether the detection changed.
+	pub fn damage(&mut self, rects: &[xcb::Rectangle], width: u32, height: u32) -> bool {
+		self.damage_at(rects, width, height, Instant::now())
+	}
+
+	fn damage_at(&mut self, rects: &[xcb::Rectangle], width: u32, height: u32, now: Instant) -> bool {
+		let minimum = (width as f32 * height as f32 * self.settings.area) as u32;
+		let biggest = rects.iter().max_by_key(|r| area(r)).filter(|r| area(r) >= minimum);
+
+		if let Some(&rect) = biggest {
+			match self.area {
+				// Players don't always damage the exact same rectangle, so any mostly
+				// overlapping one counts.
+				Some(ref current) if overlap(current, &rect) * 2 >= cmp::max(area(current), area(&rect)) => {
+					self.count += 1;
+				}
+
+				_ => {
+					self.area  = Some(rect);
+					self.since = now;
+					self.count = 1;
+				}
+			}
+
+			self.last = now;
+		}
+
+		self.check_at(now)
+	}
+
+	/// Check if the video stopped, returns whether the detection changed.
+	pub fn check(&mut self) -> bool {
+		self.check_at(Instant::now())
+	}
+
+	fn check_at(&mut self, now: Instant) -> bool {
+		let elapsed = now.duration_since(self.since);
+		let idle    = now.duration_since(self.last);
+
+		// The damages stopped coming for a few frames.
+		let stopped = idle > Duration::from_millis(cmp::max(250, (4_000.0 / self.settings.rate) as u64));
+
+		if stopped {
+			self.area  = None;
+			self.count = 0;
+		}
+
+		let active = !stopped && elapsed >= Duration::from_millis(self.settings.time) &&
+			self.count as f32 / elapsed.as_secs_f32() >= self.settings.rate;
+
+		if active != self.active {
+			self.active = active;
+			true
+		}
+		else {
+			false
+		}
+	}
+}
+
+fn area(rect: &xcb::Rectangle) -> u32 {
+	u32::from(rect.width()) * u32::from(rect.height())
+}
+
+fn overlap(a: &xcb::Rectangle, b: &xcb::Rectangle) -> u32 {
+	let left   = cmp::max(i32::from(a.x()), i32::from(b.x()));
+	let top    = cmp::max(i32::from(a.y()), i32::from(b.y()));
+	let right  = cmp::min(i32::from(a.x()) + i32::from(a.width()), i32::from(b.x()) + i32::from(b.width()));
+	let bottom = cmp::min(i32::from(a.y()) + i32::from(a.height()), i32::from(b.y()) + i32::from(b.height()));
+
+	if left < right && top < bottom {
+		((right - left) * (bottom - top)) as u32
+	}
+	else {
+		0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Feed the same rectangle at the given rate for the given time, returns
+	/// when the detection changed, if it did.
+	fn feed(detector: &mut Detector, rect: xcb::Rectangle, start: Instant, rate: u64, time: u64) -> Option<u64> {
+		let mut changed = None;
+
+		for elapsed in (0 ..= time).step_by((1_000 / rate) as usize) {
+			if detector.damage_at(&[rect], 1000, 1000, start + Duration::from_millis(elapsed)) && changed.is_none() {
+				changed = Some(elapsed);
+			}
+		}
+
+		changed
+	}
+
+	#[test]
+	fn threshold() {
+		let mut detector = Detector::new(Settings::default());
+		let start        = Instant::now();
+
+		// It takes the configured time at the configured rate.
+		assert_eq!(feed(&mut detector, xcb::Rectangle::new(0, 0, 500, 500), start, 25, 3_000), Some(2_000));
+		assert!(detector.is_active());
+	}
+
+	#[test]
+	fn rate() {
+		let mut detector = Detector::new(Settings::default());
+
+		assert_eq!(feed(&mut detector, xcb::Rectangle::new(0, 0, 500, 500), Instant::now(), 10, 5_000), None);
+		assert!(!detector.is_active());
+	}
+
+	#[test]
+	fn area() {
+		let mut detector = Detector::new(Settings::default());
+
+		// A tenth of the screen at least, like a blinking cursor doesn't.
+		assert_eq!(feed(&mut detector, xcb::Rectangle::new(0, 0, 300, 300), Instant::now(), 30, 5_000), None);
+		assert!(!detector.is_active());
+	}
+
+	#[test]
+	fn overlap() {
+		let mut detector = Detector::new(Settings::default());
+		let start        = Instant::now();
+
+		// Mostly overlapping rectangles count as the same area.
+		for elapsed in (0 ..= 3_000).step_by(40) {
+			let offset = if elapsed % 80 == 0 { 0 } else { 20 };
+			detector.damage_at(&[xcb::Rectangle::new(offset, offset, 500, 500)], 1000, 1000, start + Duration::from_millis(elapsed));
+		}
+
+		assert!(detector.is_active());
+
+		// Another area starts over.
+		detector.damage_at(&[xcb::Rectangle::new(500, 500, 400, 400)], 1000, 1000, start + Duration::from_millis(3_040));
+		assert!(!detector.is_active());
+	}
+
+	#[test]
+	fn stop() {
+		let mut detector = Detector::new(Settings::default());
+		let start        = Instant::now();
+
+		feed(&mut detector, xcb::Rectangle::new(0, 0, 500, 500), start, 25, 3_000);
+		assert!(detector.is_active());
+
+		// A few frames without damages aren't enough.
+		assert!(!detector.check_at(start + Duration::from_millis(3_200)));
+		assert!(detector.is_active());
+
+		// Then it's stopped, and has to be sustained again from scratch.
+		assert!(detector.check_at(start + Duration::from_millis(3_400)));
+		assert!(!detector.is_active());
+		assert_eq!(detector.count, 0);
+
+		let restart = start + Duration::from_millis(4_000);
+		assert_eq!(feed(&mut detector, xcb::Rectangle::new(0, 0, 500, 500), restart, 25, 3_000), Some(2_000));
+	}
+}